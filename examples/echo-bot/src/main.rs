@@ -1,11 +1,13 @@
 extern crate beryllium;
 extern crate chrono;
 extern crate env_logger;
+extern crate futures;
 extern crate log;
 
 use beryllium::{BotClient, BotService, Handler, EventData, Event};
 use chrono::offset::Utc;
 use env_logger::LogBuilder;
+use futures::Future;
 use log::{LogRecord, LogLevelFilter};
 use std::env;
 use std::fs;
@@ -16,9 +18,12 @@ pub struct EchoServer;
 impl Handler for EchoServer {
     fn handle(&self, data: EventData, client: BotClient) {
         match data.event {
-            Event::Message { ref text, ref from } => {
+            Event::Message { ref text, ref from, .. } => {
                 println!("{} received message from {}", data.bot_id, from);
-                client.send_message(text);
+                match client.send_message(text).wait() {
+                    Ok(id) => println!("Echoed message {}", id),
+                    Err(e) => println!("Cannot echo message: {}", e),
+                }
             },
             Event::ConversationMemberJoin { ref members_joined } => {
                 println!("Members joined: {:?}", members_joined);
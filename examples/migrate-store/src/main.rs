@@ -0,0 +1,35 @@
+//! Copies the bot data from a store directory (the one passed to
+//! `BotService::new`) into a SQLite database.
+//!
+//! Usage: migrate-store <store-path> <database-path>
+
+extern crate beryllium;
+
+use beryllium::{FileStore, SqliteStore, copy_bots};
+use std::env;
+use std::process;
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if args.len() != 2 {
+        eprintln!("Usage: migrate-store <store-path> <database-path>");
+        process::exit(1);
+    }
+
+    let source = FileStore::new(&args[0]);
+    let target = match SqliteStore::open(&args[1]) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Cannot open database: {}", e);
+            process::exit(1);
+        },
+    };
+
+    match copy_bots(&source, &target) {
+        Ok(count) => println!("Migrated {} bot(s)", count),
+        Err(e) => {
+            eprintln!("Migration failed: {}", e);
+            process::exit(1);
+        },
+    }
+}
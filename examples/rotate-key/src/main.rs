@@ -0,0 +1,65 @@
+//! Encrypts the bot data with a new master key (or for the first time).
+//! The store can be a directory (the one passed to `BotService::new`)
+//! or a SQLite database.
+//!
+//! Usage: rotate-key generate
+//!        rotate-key <store-path> <new-key-file> [<old-key-file>...]
+
+extern crate beryllium;
+
+use beryllium::{BotStore, EncryptedStore, FileStore, MasterKey, SqliteStore};
+use std::env;
+use std::path::Path;
+use std::process;
+
+const USAGE: &'static str = "Usage: rotate-key generate
+       rotate-key <store-path> <new-key-file> [<old-key-file>...]";
+
+fn exit_with(msg: &str) -> ! {
+    eprintln!("{}", msg);
+    process::exit(1);
+}
+
+fn read_key(path: &str) -> MasterKey {
+    MasterKey::from_file(path).unwrap_or_else(|e| {
+        exit_with(&format!("Cannot read key from {}: {}", path, e))
+    })
+}
+
+fn rotate<S: BotStore>(store: S, key: MasterKey, old_keys: Vec<MasterKey>) {
+    // Existing data could be unencrypted (if this is the first time).
+    let store = EncryptedStore::new(store, key).allow_plaintext();
+    let store = old_keys.into_iter().fold(store, |s, k| s.with_old_key(k));
+
+    match store.rotate() {
+        Ok(count) => println!("Re-encrypted {} bot(s)", count),
+        Err(e) => exit_with(&format!("Rotation failed (it can be safely re-run): {}", e)),
+    }
+}
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if args.len() == 1 && args[0] == "generate" {
+        match MasterKey::generate() {
+            Ok(key) => println!("{}", key.to_base64()),
+            Err(e) => exit_with(&format!("Cannot generate key: {}", e)),
+        }
+
+        return
+    }
+
+    if args.len() < 2 {
+        exit_with(USAGE);
+    }
+
+    let key = read_key(&args[1]);
+    let old_keys = args[2..].iter().map(|p| read_key(p)).collect();
+    if Path::new(&args[0]).is_file() {
+        match SqliteStore::open(&args[0]) {
+            Ok(s) => rotate(s, key, old_keys),
+            Err(e) => exit_with(&format!("Cannot open database: {}", e)),
+        }
+    } else {
+        rotate(FileStore::new(&args[0]), key, old_keys)
+    }
+}
@@ -2,20 +2,27 @@ use {base64, utils};
 use errors::{BerylliumError, BerylliumResult};
 use futures::{Future, Sink, future};
 use futures::sync::mpsc::Sender as FutureSender;
+use futures::sync::oneshot;
 use hyper::{Body, Method, Request, StatusCode};
-use hyper::header::{Authorization, Bearer, ContentLength, ContentType, Headers};
-use messages_proto::{Asset, Confirmation, GenericMessage, Text};
-use messages_proto::{Asset_ImageMetaData, Asset_Original, Asset_RemoteData, Confirmation_Type};
+use hyper::header::{Authorization, Bearer, ContentLength, ContentType, Headers, Location};
+use messages_proto::{Asset, ButtonActionConfirmation, Confirmation, Ephemeral, External};
+use messages_proto::{GenericMessage, MessageDelete, MessageEdit};
+use messages_proto::{Knock, Reaction, Text};
+use messages_proto::{Asset_AudioMetaData, Asset_ImageMetaData, Asset_Original};
+use messages_proto::{Asset_RemoteData, Asset_VideoMetaData, Confirmation_Type};
 use mime::Mime;
 use parking_lot::Mutex;
 use protobuf::Message;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use serde_json::{self, Value as SerdeValue};
+use std::cmp;
 use std::collections::HashMap;
 use std::mem;
 use std::sync::Arc;
-use storage::StorageManager;
-use types::{AssetData, AssetUploadRequest, Image};
+use std::time::Duration;
+use storage::{BotStore, StorageManager};
+use types::{AssetData, AssetUploadRequest, Audio, Image, Location, RemoteAsset};
+use types::{CompositeMessage, RichText, Video};
 use types::{BerylliumFuture, BotCreationData, Devices, DevicePreKeys};
 use types::{EventLoopRequest, HyperClient, MessageRequest, MessageStatus};
 use utils::MultipartWriter;
@@ -23,6 +30,9 @@ use uuid::Uuid;
 
 const HOST_ADDRESS: &'static str = "https://prod-nginz-https.wire.com";
 const MULTIPART_BOUNDARY: &'static str = "frontier";
+/// Messages bigger than this (taking all the recipient devices into account)
+/// are sent as external data.
+const EXTERNAL_MESSAGE_THRESHOLD: usize = 256 * 1024;
 lazy_static! {
     static ref MULTIPART_MIXED: Mime = {
         let mime_str = format!("multipart/mixed; boundary={}", MULTIPART_BOUNDARY);
@@ -34,6 +44,100 @@ header! {
     (ContentMd5, "Content-MD5") => [String]     // base64-encoded MD5 hash digest
 }
 
+header! {
+    (AssetToken, "Asset-Token") => [String]
+}
+
+/// Create a `GenericMessage` with a new message ID.
+fn new_generic_message() -> GenericMessage {
+    let mut message = GenericMessage::new();
+    let uuid = utils::uuid_v1();
+    message.set_message_id(uuid.to_string());
+    message
+}
+
+/// Wrap the content of the given message (if it can expire) into an `Ephemeral`,
+/// so that the message expires after the given time (in milliseconds).
+fn make_ephemeral(message: &mut GenericMessage, expire_after_millis: u64) {
+    let mut ephemeral = Ephemeral::new();
+    ephemeral.set_expire_after_millis(expire_after_millis as i64);
+    if message.has_text() {
+        ephemeral.set_text(message.take_text());
+    } else if message.has_asset() {
+        ephemeral.set_asset(message.take_asset());
+    } else if message.has_knock() {
+        ephemeral.set_knock(message.take_knock());
+    } else if message.has_location() {
+        ephemeral.set_location(message.take_location());
+    } else {
+        return
+    }
+
+    message.set_ephemeral(ephemeral);
+}
+
+/// Save the known devices of a bot. Errors are only logged, because we can
+/// always get the devices from the server (when sending a message).
+fn save_devices(storage: &StorageManager, devices: &Devices) {
+    if let Err(e) = storage.save_devices(&devices.missing) {
+        error!("Cannot save devices: {}", e);
+    }
+}
+
+/// Forget the devices which have been reported as redundant or deleted
+/// when sending a message.
+fn forget_stale_devices(storage: &StorageManager, devices: &Mutex<Devices>, report: &Devices) {
+    let mut known = devices.lock();
+    if known.remove_stale(report) {
+        info!("Removing stale devices...");
+        save_devices(storage, &known);
+    }
+}
+
+/// Large messages are encrypted only once (with AES) and sent as external data,
+/// so that only the (small) message with the key is encrypted for each device.
+/// Returns the bytes to be encrypted for each device, along with the external
+/// data (if the message is large enough to be sent that way).
+fn wrap_large_message(message_id: &str, bytes: Vec<u8>, num_clients: usize)
+                     -> BerylliumResult<(Vec<u8>, Option<String>)>
+{
+    if bytes.len() * cmp::max(num_clients, 1) <= EXTERNAL_MESSAGE_THRESHOLD {
+        return Ok((bytes, None))
+    }
+
+    info!("Sending message as external data...");
+    let enc_data = utils::encrypt(&bytes)?;
+    let mut external = External::new();
+    external.set_otr_key(enc_data.key);
+    external.set_sha256(enc_data.hash);
+    let mut message = GenericMessage::new();
+    message.set_message_id(message_id.to_owned());
+    message.set_external(external);
+    Ok((message.write_to_bytes()?, Some(base64::encode(&enc_data.data))))
+}
+
+/// Names of the data saved by bots end up in file paths, so we're strict about them.
+fn check_data_name(name: &str) -> BerylliumResult<()> {
+    if !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        Ok(())
+    } else {
+        Err(BerylliumError::Other(format!("Invalid name for bot data: {}", name)))
+    }
+}
+
+/// Metadata of the original asset for the given image.
+fn image_original(img: &Image) -> Asset_Original {
+    let img_meta = img.metadata();
+    let mut original = Asset_Original::new();
+    original.set_mime_type(img_meta.format.mime());
+    original.set_size(img.data().len() as u64);
+    let mut meta = Asset_ImageMetaData::new();
+    meta.set_width(img_meta.width as i32);
+    meta.set_height(img_meta.height as i32);
+    original.set_image(meta);
+    original
+}
+
 /// Private client to isolate some methods.
 #[derive(Clone)]
 pub struct HttpsClient {
@@ -87,14 +191,19 @@ impl HttpsClient {
         HttpsClient::request_with_request(client, request)
     }
 
-    /// Send raw message. This is usually called by `send_encrypted_message`
+    /// Send raw message. This is usually called by `send_encrypted_message`.
+    /// If a user is specified, then the missing devices are reported only for that user.
     fn send_message<T>(&self, client: &HyperClient,
-                       data: T, ignore_missing: bool)
+                       data: T, report_missing: Option<&str>)
                       -> BerylliumFuture<MessageStatus>
         where T: Serialize
     {
         info!("Sending message...");
-        let url = format!("/bot/messages?ignore_missing={}", ignore_missing);
+        let url = match report_missing {
+            Some(user_id) => format!("/bot/messages?report_missing={}", user_id),
+            None => String::from("/bot/messages?ignore_missing=false"),
+        };
+
         let f = self.request(client, Method::Post, &url, Some(data));
         let f = f.and_then(|(code, headers, body)| {
             utils::acquire_body_with_err(&headers, body).and_then(move |vec| {
@@ -108,7 +217,9 @@ impl HttpsClient {
                     future::result(res)
                 } else if code.is_success() {
                     info!("Successfully sent the message.");
-                    future::ok(MessageStatus::Sent)
+                    // The server also reports the devices which shouldn't get our messages.
+                    let devices = serde_json::from_slice::<Devices>(&vec).unwrap_or_default();
+                    future::ok(MessageStatus::Sent(devices))
                 } else {
                     let res = serde_json::from_slice::<SerdeValue>(&vec)
                                          .map_err(BerylliumError::from);
@@ -152,34 +263,76 @@ impl HttpsClient {
                                   storage: Arc<StorageManager>,
                                   devices: Arc<Mutex<Devices>>)
         -> BerylliumFuture<()>
+    {
+        self.send_encrypted_message_to(client, data, None, storage, devices)
+    }
+
+    /// Send the encrypted message only to the devices of the given user
+    /// (or to all the devices in the conversation, if there's no user).
+    pub fn send_encrypted_message_to(&self, client: &HyperClient,
+                                     data: &GenericMessage,
+                                     user_id: Option<&str>,
+                                     storage: Arc<StorageManager>,
+                                     devices: Arc<Mutex<Devices>>)
+        -> BerylliumFuture<()>
     {
         let bytes = future_try!(data.write_to_bytes());
-        let mut devices_clone = {
+        let mut targets = Devices::default();
+        targets.missing = {
             let devs = devices.lock();
-            devs.missing.clone()    // clone and release the lock
+            // clone and release the lock
+            match user_id {
+                Some(id) => devs.missing.iter().filter(|&(user, _)| user == id)
+                                .map(|(user, clients)| (user.clone(), clients.clone()))
+                                .collect(),
+                None => devs.missing.clone(),
+            }
         };
 
+        let num_clients = targets.missing.values().map(|c| c.len()).sum::<usize>();
+        let message_id = data.get_message_id().to_owned();
+        let (bytes, external_data) = future_try!(wrap_large_message(&message_id, bytes,
+                                                                    num_clients));
+
         let f = {
-            let encrypted = storage.encrypt_for_devices(&bytes, &devices_clone);
+            let encrypted = storage.encrypt_for_devices(&bytes, &targets.missing);
             let msg = MessageRequest {
                 sender: &self.client_id,
                 recipients: encrypted,
+                data: external_data.clone(),
             };
 
-            self.send_message(&client, msg, false)
+            self.send_message(&client, msg, user_id)
         };
 
         let bot_client = self.clone();
         let hyper_client = client.clone();
+        let target_user = user_id.map(String::from);
 
         let f = f.and_then(move |stat| match stat {
-            MessageStatus::Sent =>
-                Box::new(future::ok(())) as BerylliumFuture<()>,
+            MessageStatus::Sent(devs) => {
+                forget_stale_devices(&storage, &devices, &devs);
+                Box::new(future::ok(())) as BerylliumFuture<()>
+            },
             MessageStatus::Failed(devs) => {
+                forget_stale_devices(&storage, &devices, &devs);
+                targets.remove_stale(&devs);
                 info!("Getting prekeys for missing devices...");
                 let f = bot_client.get_prekeys(&hyper_client, &devs.missing);
                 let f = f.and_then(move |keys| {
-                    let mut new_data = HashMap::with_capacity(keys.len());
+                    // We may have to send the message as external data now that there
+                    // are more devices.
+                    let num_clients = targets.missing.values().map(|c| c.len()).sum::<usize>()
+                                      + keys.values().map(|c| c.len()).sum::<usize>();
+                    let (bytes, external_data) = match external_data {
+                        Some(data) => (bytes, Some(data)),
+                        None => future_try_box!(wrap_large_message(&message_id, bytes,
+                                                                   num_clients)),
+                    };
+
+                    // The server has rejected the entire message, so we should send it
+                    // to the devices we already know, along with the missing ones.
+                    let mut new_data = storage.encrypt_for_devices(&bytes, &targets.missing);
                     for (user_id, clients) in &keys {
                         for (client_id, prekey) in clients {
                             let prekey = future_try_box!(base64::decode(&prekey.key));
@@ -188,27 +341,35 @@ impl HttpsClient {
                             let res = storage.encrypt(user_id.as_str(), client_id,
                                                       &bytes, &prekey);
                             let encrypted = future_try_box!(res);
-                            clients.entry(client_id.as_str()).or_insert(encrypted);
+                            clients.insert(client_id.as_str(), encrypted);
 
                             // We've successfully encrypted the message for a new device
                             // with a new prekey. Since we've already stored the session,
                             // we can safely update our devices.
-                            let clients = devices_clone.entry(user_id.clone())
+                            let mut known = devices.lock();
+                            let clients = known.missing.entry(user_id.clone())
                                                        .or_insert(vec![]);
-                            clients.push(client_id.to_owned());
+                            if !clients.contains(client_id) {
+                                clients.push(client_id.to_owned());
+                            }
                         }
                     }
 
-                    devices.lock().missing = devices_clone;
+                    save_devices(&storage, &devices.lock());
                     let message = MessageRequest {
                         sender: &bot_client.client_id,
                         recipients: new_data,
+                        data: external_data,
                     };
 
-                    let f = bot_client.send_message(&hyper_client, message, false);
+                    let f = bot_client.send_message(&hyper_client, message,
+                                                    target_user.as_ref().map(|s| s.as_str()));
                     let f = f.and_then(move |stat| {
                         match stat {
-                            MessageStatus::Sent => future::ok(()),
+                            MessageStatus::Sent(devs) => {
+                                forget_stale_devices(&storage, &devices, &devs);
+                                future::ok(())
+                            },
                             MessageStatus::Failed(_) => {
                                 let msg = "Cannot send message! Failed after device check";
                                 future::err(BerylliumError::Other(String::from(msg)))
@@ -234,16 +395,32 @@ impl HttpsClient {
         -> BerylliumFuture<()>
     {
         info!("Sending confirmation message...");
-        let mut message = GenericMessage::new();
-        let uuid = utils::uuid_v1();
-        message.set_message_id(uuid.to_string());
+        let mut message = new_generic_message();
         let mut confirmation = Confirmation::new();
-        confirmation.set_message_id(message_id.to_owned());
-        confirmation.set_field_type(Confirmation_Type::DELIVERED);
+        confirmation.set_first_message_id(message_id.to_owned());
+        confirmation.set_field_type(Confirmation_Type::DELIVERED as i32);
         message.set_confirmation(confirmation);
         self.send_encrypted_message(client, &message, storage, devices)
     }
 
+    /// Let the user who clicked a button (in a composite message sent by this bot)
+    /// know that the action has been received.
+    pub fn send_button_action_confirmation(&self, client: &HyperClient,
+                                           reference_message_id: &str,
+                                           button_id: &str, user_id: &str,
+                                           storage: Arc<StorageManager>,
+                                           devices: Arc<Mutex<Devices>>)
+        -> BerylliumFuture<()>
+    {
+        info!("Sending button action confirmation...");
+        let mut message = new_generic_message();
+        let mut confirmation = ButtonActionConfirmation::new();
+        confirmation.set_reference_message_id(reference_message_id.to_owned());
+        confirmation.set_button_id(button_id.to_owned());
+        message.set_buttonActionConfirmation(confirmation);
+        self.send_encrypted_message_to(client, &message, Some(user_id), storage, devices)
+    }
+
     /// Upload a given asset to Wire servers and return the asset key and token.
     /// Note that the `asset_data` is encrypted at this point.
     fn upload_asset<T>(&self, client: &HyperClient, req_data: T,
@@ -297,6 +474,122 @@ impl HttpsClient {
 
         Box::new(f)
     }
+
+    /// Encrypt and upload the given asset data, and return the `Asset`
+    /// (with the given metadata of the original asset) for sending.
+    fn upload_encrypted_asset(&self, client: &HyperClient, data: &[u8],
+                              asset_type: ContentType, original: Asset_Original)
+                             -> BerylliumFuture<Asset>
+    {
+        let enc_data = future_try!(utils::encrypt(data));
+        let req_data = AssetUploadRequest {
+            public: false,
+            retention: "volatile",
+        };
+
+        let f = self.upload_asset(client, req_data, &enc_data.data, asset_type);
+        let f = f.map(move |asset_data| {
+            let mut asset = Asset::new();
+            asset.set_original(original);
+            let mut upload_data = Asset_RemoteData::new();
+            upload_data.set_otr_key(enc_data.key);
+            upload_data.set_sha256(enc_data.hash);
+            upload_data.set_asset_id(asset_data.key);
+            upload_data.set_asset_token(asset_data.token);
+            asset.set_uploaded(upload_data);
+            asset
+        });
+
+        Box::new(f)
+    }
+
+    /// Encrypt and upload the given asset data, and send an asset message
+    /// (with the given metadata of the original asset) to the conversation.
+    fn send_asset(&self, client: &HyperClient, message_id: &str, data: &[u8],
+                  asset_type: ContentType, original: Asset_Original,
+                  expiry: Option<u64>, storage: Arc<StorageManager>,
+                  devices: Arc<Mutex<Devices>>) -> BerylliumFuture<()>
+    {
+        let f = self.upload_encrypted_asset(client, data, asset_type, original);
+        let (c, bot_client) = (client.clone(), self.clone());
+        let message_id = message_id.to_owned();
+
+        let f = f.and_then(move |asset| {
+            let mut message = GenericMessage::new();
+            message.set_message_id(message_id);
+            message.set_asset(asset);
+            if let Some(millis) = expiry {
+                make_ephemeral(&mut message, millis);
+            }
+
+            bot_client.send_encrypted_message(&c, &message, storage, devices)
+        });
+
+        Box::new(f)
+    }
+
+    /// Download an (encrypted) asset from Wire servers. The server usually
+    /// redirects us to the actual location of the asset, which we follow.
+    fn download_asset(&self, client: &HyperClient, asset_id: &str,
+                      asset_token: Option<&str>) -> BerylliumFuture<Vec<u8>>
+    {
+        let url = format!("/bot/assets/{}", asset_id);
+        let mut request = self.prepare_request_for_url(Method::Get, &url);
+        if let Some(token) = asset_token {
+            request.headers_mut().set(AssetToken(token.to_owned()));
+        }
+
+        let hyper_client = client.clone();
+        let f = HttpsClient::request_with_request(client, request)
+                            .and_then(move |(code, headers, body)| {
+            if !code.is_redirection() {
+                return HttpsClient::acquire_asset(code, headers, body)
+            }
+
+            let location = match headers.get::<Location>() {
+                Some(l) => l.to_string(),
+                None => {
+                    let msg = String::from("Missing location for asset redirect");
+                    return Box::new(future::err(BerylliumError::Other(msg))) as BerylliumFuture<_>
+                },
+            };
+
+            info!("Following redirect for asset...");
+            let uri = future_try_box!(location.parse().map_err(|e| {
+                BerylliumError::Other(format!("Invalid asset location: {}", e))
+            }));
+
+            // The redirected URL is pre-signed, so we shouldn't authorize this request.
+            let request = Request::new(Method::Get, uri);
+            let f = HttpsClient::request_with_request(&hyper_client, request)
+                                .and_then(|(code, headers, body)| {
+                HttpsClient::acquire_asset(code, headers, body)
+            });
+
+            Box::new(f) as BerylliumFuture<_>
+        });
+
+        Box::new(f)
+    }
+
+    /// Acquire the asset from the response body of a download request.
+    fn acquire_asset(code: StatusCode, headers: Headers, body: Body)
+                    -> BerylliumFuture<Vec<u8>>
+    {
+        let f = utils::acquire_body_with_err(&headers, body).and_then(move |vec| {
+            if code.is_success() {
+                info!("Successfully downloaded asset.");
+                future::ok(vec)
+            } else {
+                let res = serde_json::from_slice::<SerdeValue>(&vec)
+                                     .map_err(BerylliumError::from);
+                let msg = format!("Error downloading asset. Response: {:?}", res);
+                future::err(BerylliumError::Other(msg))
+            }
+        });
+
+        Box::new(f)
+    }
 }
 
 impl<'a> From<&'a BotCreationData> for HttpsClient {
@@ -315,19 +608,41 @@ pub struct BotData {
     /// `Arc<Mutex<T>>` because it's shared with the global bot data.
     /// Whenever we get new devices, we'll update this.
     pub devices: Arc<Mutex<Devices>>,
+    /// Default expiry (in milliseconds) for the messages sent to this conversation.
+    pub ephemeral_timeout: Arc<Mutex<Option<u64>>>,
+    /// Held while loading, changing and saving the data of the bot.
+    pub data_lock: Arc<Mutex<()>>,
 }
 
 impl BotData {
-    pub fn from_storage(bot_id: Uuid) -> BerylliumResult<BotData> {
-        let storage = StorageManager::new(bot_id)?;
+    pub fn from_storage(bot_id: Uuid, store: Arc<BotStore>) -> BerylliumResult<BotData> {
+        let storage = StorageManager::new(bot_id, store)?;
         let store_data: BotCreationData = storage.load_state()?;
+        // We'll get the new devices (if any) when we send a message.
+        let devices = Devices {
+            missing: storage.load_devices()?,
+            ..Devices::default()
+        };
+
         Ok(BotData {
             storage: Arc::new(storage),
             client: HttpsClient::from(&store_data),
+            ephemeral_timeout: Arc::new(Mutex::new(store_data.ephemeral_timeout)),
             data: store_data,
-            devices: Arc::new(Mutex::new(Devices::default())),
+            devices: Arc::new(Mutex::new(devices)),
+            data_lock: Arc::new(Mutex::new(())),
         })
     }
+
+    /// Persist the state of this bot (along with its current ephemeral timeout).
+    pub fn save_state(&mut self) -> BerylliumResult<()> {
+        // Hold the lock while saving, so that we don't race with the client
+        // changing (and saving) the timeout.
+        let shared = self.ephemeral_timeout.clone();
+        let timeout = shared.lock();
+        self.data.ephemeral_timeout = *timeout;
+        self.storage.save_state(&self.data)
+    }
 }
 
 #[derive(Clone)]
@@ -338,6 +653,12 @@ pub struct BotClient {
     storage: Arc<StorageManager>,
     devices: Arc<Mutex<Devices>>,
     event_loop_sender: FutureSender<EventLoopRequest<()>>,
+    /// Default expiry (in milliseconds) shared with the bot data.
+    ephemeral_timeout: Arc<Mutex<Option<u64>>>,
+    /// Expiry for messages sent using this client (overrides the default).
+    expiry_override: Option<Option<u64>>,
+    /// Lock for the bot's data (shared with the bot data).
+    data_lock: Arc<Mutex<()>>,
 }
 
 impl<'a> From<(&'a BotData, &'a FutureSender<EventLoopRequest<()>>)> for BotClient {
@@ -348,79 +669,413 @@ impl<'a> From<(&'a BotData, &'a FutureSender<EventLoopRequest<()>>)> for BotClie
             sender: data.0.data.client.clone(),
             devices: data.0.devices.clone(),
             event_loop_sender: data.1.clone(),
+            ephemeral_timeout: data.0.ephemeral_timeout.clone(),
+            expiry_override: None,
+            data_lock: data.0.data_lock.clone(),
         }
     }
 }
 
 impl BotClient {
-    /// Send a user text message to the conversation associated with the bot instance.
-    pub fn send_message(&self, text: &str) {
-        let text = text.to_owned();
+    /// Queue a request into the event loop, and return a `Future` which
+    /// resolves once the event loop has resolved the request.
+    fn queue_request<T, F>(&self, call: F) -> BerylliumFuture<T>
+        where T: Send + 'static,
+              F: Fn(&HyperClient) -> BerylliumFuture<T> + Send + 'static
+    {
+        let (tx, rx) = oneshot::channel();
+        // Event loop requests are `Fn` closures, but we can send the result only once.
+        let tx = Mutex::new(Some(tx));
+        let call_closure = Box::new(move |c: &HyperClient| {
+            let tx = tx.lock().take();
+            let f = call(c).then(move |res| {
+                if let Err(ref e) = res {
+                    info!("Error resolving request: {}", e);
+                }
+
+                if let Some(tx) = tx {
+                    let _ = tx.send(res);
+                }
+
+                Ok::<_, BerylliumError>(())
+            });
+
+            Box::new(f) as BerylliumFuture<()>
+        });
+
+        future_try!(self.event_loop_sender.clone().send(call_closure).wait().map_err(|e| {
+            BerylliumError::Other(format!("Cannot queue request in event loop: {}", e))
+        }));
+
+        let f = rx.then(|res| match res {
+            Ok(res) => res,
+            Err(_) => {
+                let msg = String::from("Event loop dropped the request");
+                Err(BerylliumError::Other(msg))
+            },
+        });
+
+        Box::new(f)
+    }
+
+    /// ID of the bot instance.
+    pub fn bot_id(&self) -> Uuid {
+        *self.storage.id()
+    }
+
+    /// Expiry (in milliseconds) for the messages sent using this client.
+    fn expiry(&self) -> Option<u64> {
+        match self.expiry_override {
+            Some(expiry) => expiry,
+            None => *self.ephemeral_timeout.lock(),
+        }
+    }
+
+    /// Set the default timeout after which the messages sent to this conversation
+    /// should expire (i.e., ephemeral messages). `None` disables this.
+    ///
+    /// The timeout is persisted along with the state of the bot.
+    pub fn set_ephemeral_timeout(&self, timeout: Option<Duration>) -> BerylliumResult<()> {
+        let mut current = self.ephemeral_timeout.lock();
+        let mut data: BotCreationData = self.storage.load_state()?;
+        data.ephemeral_timeout = timeout.map(utils::duration_millis);
+        self.storage.save_state(&data)?;
+        *current = data.ephemeral_timeout;
+        Ok(())
+    }
+
+    /// Get a client whose messages (texts, assets, knocks and locations) expire
+    /// after the given timeout, regardless of the default for this conversation.
+    /// `None` means that the messages sent using that client never expire.
+    ///
+    /// ```rust,ignore
+    /// client.ephemeral(Some(Duration::from_secs(30))).send_message("Secret!");
+    /// ```
+    pub fn ephemeral(&self, timeout: Option<Duration>) -> BotClient {
+        let mut client = self.clone();
+        client.expiry_override = Some(timeout.map(utils::duration_millis));
+        client
+    }
+
+    /// Queue the given message to be sent to the conversation, and return
+    /// a `Future` which resolves to the message ID once it's been sent.
+    fn send_generic_message(&self, mut message: GenericMessage) -> BerylliumFuture<String> {
+        if let Some(millis) = self.expiry() {
+            make_ephemeral(&mut message, millis);
+        }
+
         let (client, storage, devices) =
             (self.inner.clone(), self.storage.clone(), self.devices.clone());
-
-        let call_closure = Box::new(move |c: &HyperClient| {
-            let mut message = GenericMessage::new();
-            let uuid = utils::uuid_v1();
-            message.set_message_id(uuid.to_string());
-            let mut txt = Text::new();
-            txt.set_content(text.clone());
-            message.set_text(txt);
+        let message_id = message.get_message_id().to_owned();
+        let f = self.queue_request(move |c: &HyperClient| {
             client.send_encrypted_message(c, &message, storage.clone(), devices.clone())
         });
 
-        self.event_loop_sender.clone().send(call_closure).wait().map_err(|e| {
-            error!("Cannot queue user message in event loop: {}", e);
-        }).ok();
+        Box::new(f.map(move |_| message_id))
     }
 
-    /// Send an user image to the associated conversation. Use the exported
-    /// `Image` to open an image (from path, reader, or buffer).
-    pub fn send_image(&self, img: Arc<Image>) {
+    /// Send a user text message to the conversation associated with the bot instance.
+    ///
+    /// The message is queued right away, and the returned `Future` resolves to
+    /// the ID of the message once it's been sent (or to the error in sending it).
+    pub fn send_message(&self, text: &str) -> BerylliumFuture<String> {
+        let mut message = new_generic_message();
+        let mut txt = Text::new();
+        txt.set_content(text.to_owned());
+        message.set_text(txt);
+        self.send_generic_message(message)
+    }
+
+    /// Send a text message with rich content (mentions and link previews) to the
+    /// associated conversation. Use the exported `RichText` to build the message.
+    ///
+    /// Images of the link previews (if any) are uploaded before sending the message.
+    pub fn send_rich_text(&self, text: RichText) -> BerylliumFuture<String> {
+        let images = text.preview_images();
+        if images.iter().all(|img| img.is_none()) {
+            let mut message = new_generic_message();
+            message.set_text(text.into());
+            return self.send_generic_message(message)
+        }
+
         let (client, storage, devices) =
             (self.inner.clone(), self.storage.clone(), self.devices.clone());
+        let text: Text = text.into();
+        let (message_id, expiry) = (utils::uuid_v1().to_string(), self.expiry());
+        let id = message_id.clone();
 
-        let call_closure = Box::new(move |c: &HyperClient| {
-            let enc_data = future_try_box!(utils::encrypt(img.data()));
-            let img_size = img.data().len();
-            let img_meta = img.metadata();
-            let req_data = AssetUploadRequest {
-                public: false,
-                retention: "volatile",
-            };
+        let f = self.queue_request(move |c: &HyperClient| {
+            let uploads = images.iter().map(|img| match *img {
+                Some(ref img) => {
+                    let f = client.upload_encrypted_asset(c, img.data(),
+                                                          img.metadata().format.into(),
+                                                          image_original(img));
+                    Box::new(f.map(Some)) as BerylliumFuture<_>
+                },
+                None => Box::new(future::ok(None)) as BerylliumFuture<_>,
+            }).collect::<Vec<_>>();
 
-            let f = client.upload_asset(c, req_data, &enc_data.data, img_meta.format.into());
             let (c, client, storage, devices) =
                 (c.clone(), client.clone(), storage.clone(), devices.clone());
+            let (mut text, id) = (text.clone(), id.clone());
+            let f = future::join_all(uploads).and_then(move |assets| {
+                for (preview, asset) in text.mut_link_preview().iter_mut().zip(assets) {
+                    if let Some(asset) = asset {
+                        preview.set_image(asset);
+                    }
+                }
 
-            let f = f.and_then(move |asset_data| {
                 let mut message = GenericMessage::new();
-                let uuid = utils::uuid_v1();
-                message.set_message_id(uuid.to_string());
-                let mut asset = Asset::new();
-                let mut original = Asset_Original::new();
-                original.set_mime_type(img_meta.format.mime());
-                original.set_size(img_size as u64);
-                let mut meta = Asset_ImageMetaData::new();
-                meta.set_width(img_meta.width as i32);
-                meta.set_height(img_meta.height as i32);
-                original.set_image(meta);
-                asset.set_original(original);
-                let mut upload_data = Asset_RemoteData::new();
-                upload_data.set_otr_key(enc_data.key);
-                upload_data.set_sha256(enc_data.hash);
-                upload_data.set_asset_id(asset_data.key);
-                upload_data.set_asset_token(asset_data.token);
-                asset.set_uploaded(upload_data);
-                message.set_asset(asset);
-                client.send_encrypted_message(&c, &message, storage.clone(), devices.clone())
+                message.set_message_id(id);
+                message.set_text(text);
+                if let Some(millis) = expiry {
+                    make_ephemeral(&mut message, millis);
+                }
+
+                client.send_encrypted_message(&c, &message, storage, devices)
             });
 
-            Box::new(f)
+            Box::new(f) as BerylliumFuture<_>
+        });
+
+        Box::new(f.map(move |_| message_id))
+    }
+
+    /// Ping the associated conversation. A hot knock is a louder ping.
+    pub fn knock(&self, hot: bool) -> BerylliumFuture<String> {
+        let mut message = new_generic_message();
+        let mut knock = Knock::new();
+        knock.set_hot_knock(hot);
+        message.set_knock(knock);
+        self.send_generic_message(message)
+    }
+
+    /// Share a location in the associated conversation.
+    pub fn send_location(&self, location: Location) -> BerylliumFuture<String> {
+        let mut message = new_generic_message();
+        message.set_location(location.into());
+        self.send_generic_message(message)
+    }
+
+    /// Send a message with texts and buttons to the associated conversation.
+    /// Use the exported `CompositeMessage` to build the message.
+    ///
+    /// When a user clicks a button, the handler gets an `Event::ButtonAction`
+    /// (which is automatically acknowledged).
+    ///
+    /// ```rust,ignore
+    /// let menu = CompositeMessage::new().text("Pick one:")
+    ///                                   .button("tea", "Tea")
+    ///                                   .button("coffee", "Coffee");
+    /// client.send_composite(menu);
+    /// ```
+    pub fn send_composite(&self, composite: CompositeMessage) -> BerylliumFuture<String> {
+        let mut message = new_generic_message();
+        message.set_composite(composite.into());
+        self.send_generic_message(message)
+    }
+
+    /// Replace the text of a message (previously sent by this bot) with the given text.
+    ///
+    /// Note that the edited message gets a new ID (to which the returned `Future`
+    /// resolves), and that ID should be used for editing the message again.
+    pub fn edit_message(&self, message_id: &str, text: &str) -> BerylliumFuture<String> {
+        let mut message = new_generic_message();
+        let mut edit = MessageEdit::new();
+        edit.set_replacing_message_id(message_id.to_owned());
+        let mut txt = Text::new();
+        txt.set_content(text.to_owned());
+        edit.set_text(txt);
+        message.set_edited(edit);
+        self.send_generic_message(message)
+    }
+
+    /// Replace a composite message (previously sent by this bot) with the given one.
+    /// As with text edits, the returned `Future` resolves to the new message ID.
+    pub fn edit_composite(&self, message_id: &str,
+                          composite: CompositeMessage) -> BerylliumFuture<String> {
+        let mut message = new_generic_message();
+        let mut edit = MessageEdit::new();
+        edit.set_replacing_message_id(message_id.to_owned());
+        edit.set_composite(composite.into());
+        message.set_edited(edit);
+        self.send_generic_message(message)
+    }
+
+    /// Delete a message (previously sent by this bot) for everyone in the conversation.
+    pub fn delete_message(&self, message_id: &str) -> BerylliumFuture<String> {
+        let mut message = new_generic_message();
+        let mut delete = MessageDelete::new();
+        delete.set_message_id(message_id.to_owned());
+        message.set_deleted(delete);
+        self.send_generic_message(message)
+    }
+
+    /// Let the sender of a message know that it's been read by this bot.
+    /// Like Wire clients, the confirmation is sent only to the sender's devices.
+    pub fn mark_read(&self, message_id: &str, sender: &Uuid) -> BerylliumFuture<String> {
+        let mut message = new_generic_message();
+        let mut confirmation = Confirmation::new();
+        confirmation.set_first_message_id(message_id.to_owned());
+        confirmation.set_field_type(Confirmation_Type::READ as i32);
+        message.set_confirmation(confirmation);
+
+        let (client, storage, devices) =
+            (self.inner.clone(), self.storage.clone(), self.devices.clone());
+        let (message_id, user_id) = (message.get_message_id().to_owned(), sender.to_string());
+        let f = self.queue_request(move |c: &HyperClient| {
+            client.send_encrypted_message_to(c, &message, Some(&user_id),
+                                             storage.clone(), devices.clone())
+        });
+
+        Box::new(f.map(move |_| message_id))
+    }
+
+    /// React to a message with the given emoji (Wire clients use "❤️" for likes).
+    /// An empty emoji removes the previous reaction of this bot.
+    pub fn react(&self, message_id: &str, emoji: &str) -> BerylliumFuture<String> {
+        let mut message = new_generic_message();
+        let mut reaction = Reaction::new();
+        reaction.set_message_id(message_id.to_owned());
+        reaction.set_emoji(emoji.to_owned());
+        message.set_reaction(reaction);
+        self.send_generic_message(message)
+    }
+
+    /// Persist some (JSON-serializable) data for this bot instance. The name
+    /// should contain only alphanumeric characters, dashes and underscores.
+    pub fn save_data<T>(&self, name: &str, data: &T) -> BerylliumResult<()>
+        where T: Serialize
+    {
+        check_data_name(name)?;
+        self.storage.save_data(name, data)
+    }
+
+    /// Load the data previously saved (with the given name) for this bot instance.
+    pub fn load_data<T>(&self, name: &str) -> BerylliumResult<Option<T>>
+        where for<'de> T: Deserialize<'de>
+    {
+        check_data_name(name)?;
+        self.storage.load_data(name)
+    }
+
+    /// Call the function while holding the lock for this bot's data, so that
+    /// concurrent events don't overwrite each other's changes when they load,
+    /// change and save the same data. Note that the lock isn't reentrant.
+    pub fn with_data_lock<T, F>(&self, call: F) -> T
+        where F: FnOnce() -> T
+    {
+        let _lock = self.data_lock.lock();
+        call()
+    }
+
+    /// Send an user image to the associated conversation. Use the exported
+    /// `Image` to open an image (from path, reader, or buffer).
+    pub fn send_image(&self, img: Arc<Image>) -> BerylliumFuture<String> {
+        let (client, storage, devices) =
+            (self.inner.clone(), self.storage.clone(), self.devices.clone());
+        let img_meta = img.metadata();
+        let original = image_original(&img);
+
+        let (message_id, expiry) = (utils::uuid_v1().to_string(), self.expiry());
+        let id = message_id.clone();
+        let f = self.queue_request(move |c: &HyperClient| {
+            client.send_asset(c, &id, img.data(), img_meta.format.into(), original.clone(),
+                              expiry, storage.clone(), devices.clone())
+        });
+
+        Box::new(f.map(move |_| message_id))
+    }
+
+    /// Send an audio file to the associated conversation. Use the exported
+    /// `Audio` to read a WAV file or to specify the metadata of other formats.
+    pub fn send_audio(&self, audio: Arc<Audio>) -> BerylliumFuture<String> {
+        let (client, storage, devices) =
+            (self.inner.clone(), self.storage.clone(), self.devices.clone());
+        let audio_meta = audio.metadata();
+        let mut original = Asset_Original::new();
+        original.set_mime_type(audio.mime().to_string());
+        original.set_size(audio.data().len() as u64);
+        let mut meta = Asset_AudioMetaData::new();
+        meta.set_duration_in_millis(audio_meta.duration_in_millis);
+        if let Some(ref loudness) = audio_meta.normalized_loudness {
+            meta.set_normalized_loudness(loudness.clone());
+        }
+
+        original.set_audio(meta);
+
+        let (message_id, expiry) = (utils::uuid_v1().to_string(), self.expiry());
+        let id = message_id.clone();
+        let f = self.queue_request(move |c: &HyperClient| {
+            client.send_asset(c, &id, audio.data(), ContentType(audio.mime().clone()),
+                              original.clone(), expiry, storage.clone(), devices.clone())
+        });
+
+        Box::new(f.map(move |_| message_id))
+    }
+
+    /// Send a video file to the associated conversation. Use the exported
+    /// `Video` to specify the video along with its metadata.
+    pub fn send_video(&self, video: Arc<Video>) -> BerylliumFuture<String> {
+        let (client, storage, devices) =
+            (self.inner.clone(), self.storage.clone(), self.devices.clone());
+        let video_meta = video.metadata();
+        let mut original = Asset_Original::new();
+        original.set_mime_type(video.mime().to_string());
+        original.set_size(video.data().len() as u64);
+        let mut meta = Asset_VideoMetaData::new();
+        meta.set_width(video_meta.width as i32);
+        meta.set_height(video_meta.height as i32);
+        meta.set_duration_in_millis(video_meta.duration_in_millis);
+        original.set_video(meta);
+
+        let (message_id, expiry) = (utils::uuid_v1().to_string(), self.expiry());
+        let id = message_id.clone();
+        let f = self.queue_request(move |c: &HyperClient| {
+            client.send_asset(c, &id, video.data(), ContentType(video.mime().clone()),
+                              original.clone(), expiry, storage.clone(), devices.clone())
+        });
+
+        Box::new(f.map(move |_| message_id))
+    }
+
+    /// Send a file of any type (with the given name and MIME type)
+    /// to the associated conversation.
+    pub fn send_file(&self, name: &str, mime: Mime, data: Vec<u8>) -> BerylliumFuture<String> {
+        let (client, storage, devices) =
+            (self.inner.clone(), self.storage.clone(), self.devices.clone());
+        let mut original = Asset_Original::new();
+        original.set_mime_type(mime.to_string());
+        original.set_size(data.len() as u64);
+        original.set_name(name.to_owned());
+
+        let (message_id, expiry) = (utils::uuid_v1().to_string(), self.expiry());
+        let id = message_id.clone();
+        let f = self.queue_request(move |c: &HyperClient| {
+            client.send_asset(c, &id, &data, ContentType(mime.clone()), original.clone(),
+                              expiry, storage.clone(), devices.clone())
+        });
+
+        Box::new(f.map(move |_| message_id))
+    }
+
+    /// Download an asset shared in the conversation, verify its SHA-256 hash,
+    /// and decrypt it. The returned `Future` resolves to the plaintext bytes.
+    pub fn download_asset(&self, asset: &RemoteAsset) -> BerylliumFuture<Vec<u8>> {
+        let asset_id = match asset.asset_id {
+            Some(ref id) => id.clone(),
+            None => {
+                let msg = String::from("Asset doesn't have an ID");
+                return Box::new(future::err(BerylliumError::Other(msg)))
+            },
+        };
+
+        let (client, token) = (self.inner.clone(), asset.asset_token.clone());
+        let (key, hash) = (asset.otr_key.clone(), asset.sha256.clone());
+        let f = self.queue_request(move |c: &HyperClient| {
+            client.download_asset(c, &asset_id, token.as_ref().map(|s| s.as_str()))
         });
 
-        self.event_loop_sender.clone().send(call_closure).wait().map_err(|e| {
-            error!("Cannot queue user image in event loop: {}", e);
-        }).ok();
+        Box::new(f.and_then(move |bytes| utils::decrypt(&bytes, &key, &hash)))
     }
 }
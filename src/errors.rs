@@ -1,11 +1,11 @@
 use base64::DecodeError as B64DecodeError;
-use cryptobox::CBoxError;
-use cryptobox::store::file::FileStore;
 use hyper::Error as HyperError;
 use image::ImageError;
 use openssl::error::ErrorStack;
 use proteus::{DecodeError, EncodeError};
 use protobuf::error::ProtobufError;
+#[cfg(feature = "sqlite")]
+use rusqlite::Error as SqliteError;
 use serde_json::error::Error as SerdeError;
 use std::error::Error;
 use std::fmt::{self, Display};
@@ -18,7 +18,8 @@ pub type BerylliumResult<T> = Result<T, BerylliumError>;
 #[derive(Debug)]
 pub enum BerylliumError {
     Io(io::Error),
-    CBox(CBoxError<FileStore>),
+    /// Errors in (Proteus) sessions, such as decryption failures.
+    Session(String),
     Openssl(ErrorStack),
     Encode(EncodeError),
     Decode(DecodeError),
@@ -29,6 +30,8 @@ pub enum BerylliumError {
     Base64(B64DecodeError),
     Protobuf(ProtobufError),
     Uuid(UuidError),
+    #[cfg(feature = "sqlite")]
+    Sqlite(SqliteError),
     Other(String),
     Unreachable,
 }
@@ -36,7 +39,7 @@ pub enum BerylliumError {
 impl Display for BerylliumError {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         match *self {
-            BerylliumError::CBox(ref e)     => write!(f, "Cryptobox error: {}", e),
+            BerylliumError::Session(ref e)  => write!(f, "Session error: {}", e),
             BerylliumError::Io(ref e)       => write!(f, "I/O error: {}", e),
             BerylliumError::Openssl(ref e)  => write!(f, "Openssl error: {}", e),
             BerylliumError::Encode(ref e)   => write!(f, "Encode error: {}", e),
@@ -48,6 +51,8 @@ impl Display for BerylliumError {
             BerylliumError::Base64(ref e)   => write!(f, "Base64 decode error: {}", e),
             BerylliumError::Protobuf(ref e) => write!(f, "Protobuf error: {}", e),
             BerylliumError::Uuid(ref e)     => write!(f, "UUID parse error: {}", e),
+            #[cfg(feature = "sqlite")]
+            BerylliumError::Sqlite(ref e)   => write!(f, "SQLite error: {}", e),
             BerylliumError::Other(ref e)    => write!(f, "Unknown error: {}", e),
             BerylliumError::Unreachable     => f.write_str("Entered unreachable code!"),
         }
@@ -61,7 +66,6 @@ impl Error for BerylliumError {
 
     fn cause(&self) -> Option<&Error> {
         match *self {
-            BerylliumError::CBox(ref e)     => Some(e),
             BerylliumError::Openssl(ref e)  => Some(e),
             BerylliumError::Image(ref e)    => Some(e),
             BerylliumError::Io(ref e)       => Some(e),
@@ -72,6 +76,8 @@ impl Error for BerylliumError {
             BerylliumError::Protobuf(ref e) => Some(e),
             BerylliumError::Uuid(ref e)     => Some(e),
             BerylliumError::Serde(ref e)    => Some(e),
+            #[cfg(feature = "sqlite")]
+            BerylliumError::Sqlite(ref e)   => Some(e),
             _ => None,
         }
     }
@@ -93,8 +99,9 @@ impl_error!(ErrorStack => Openssl);
 impl_error!(DecodeError => Decode);
 impl_error!(EncodeError => Encode);
 impl_error!(HyperError => Hyper);
-impl_error!(CBoxError<FileStore> => CBox);
 impl_error!(SerdeError => Serde);
 impl_error!(B64DecodeError => Base64);
 impl_error!(ProtobufError => Protobuf);
 impl_error!(UuidError => Uuid);
+#[cfg(feature = "sqlite")]
+impl_error!(SqliteError => Sqlite);
@@ -1,4 +1,4 @@
-use {protobuf, utils};
+use {base64, protobuf, utils};
 use client::{BotClient, BotData};
 use errors::{BerylliumError, BerylliumResult};
 use futures::{Future, Sink, future};
@@ -7,14 +7,20 @@ use futures_cpupool::{Builder, CpuFuture, CpuPool};
 use hyper::{Body, Error as HyperError, Headers, Method, StatusCode};
 use hyper::header::{Authorization, Bearer};
 use hyper::server::{Service, Request, Response};
-use messages_proto::GenericMessage;
+use messages_proto::{Confirmation_Type, EncryptionAlgorithm, GenericMessage};
 use parking_lot::Mutex;
+use protobuf::ProtobufEnum;
 use serde_json::{self, Value as SerdeValue};
-use storage::StorageManager;
+use storage::{BotStore, StorageManager};
+use std::cmp;
 use std::collections::HashMap;
 use std::sync::Arc;
-use types::{BotCreationData, BotCreationResponse, Event, EventData};
-use types::{ConversationData, ConversationEventType, MessageData, Member};
+use std::time::Duration;
+use types::{AssetInfo, AvailabilityStatus, BotCreationData, BotCreationResponse};
+use types::{CompositeItem, ConfirmationType};
+use types::{Event, EventData};
+use types::{ConversationData, ConversationEventType, LinkPreview, MessageData};
+use types::{Location, Member, Mention, Quote};
 use types::{HyperClient, EventLoopRequest};
 use uuid::Uuid;
 
@@ -49,15 +55,32 @@ pub struct BotHandler<H> {
     /// Any bot request to Wire server is queued into the event loop
     /// using this sender.
     event_loop_sender: FutureSender<EventLoopRequest<()>>,
+    /// Backend for persisting the bot data.
+    store: Arc<BotStore>,
 }
 
 impl<H: Handler> BotHandler<H> {
-    pub fn new(handler: Arc<H>, sender: FutureSender<EventLoopRequest<()>>) -> BotHandler<H> {
+    pub fn new(handler: Arc<H>, sender: FutureSender<EventLoopRequest<()>>,
+               store: Arc<BotStore>) -> BotHandler<H> {
         BotHandler {
             handler: handler,
             pool: Arc::new(Builder::new().create()),
             bot_data: Arc::new(Mutex::new(HashMap::new())),
             event_loop_sender: sender,
+            store: store,
+        }
+    }
+}
+
+// Connections share the handler (and hence, the bot data).
+impl<H> Clone for BotHandler<H> {
+    fn clone(&self) -> BotHandler<H> {
+        BotHandler {
+            handler: self.handler.clone(),
+            pool: self.pool.clone(),
+            bot_data: self.bot_data.clone(),
+            event_loop_sender: self.event_loop_sender.clone(),
+            store: self.store.clone(),
         }
     }
 }
@@ -115,15 +138,19 @@ impl<H: Handler> Service for BotHandler<H> {
 
         // FIXME: Better way to detect relative URL paths?
         match (split.next(), split.next(), split.next(), split.next()) {
-            (Some("bots"), None, None, None) => parse_json_and!(create_bot),
+            (Some("bots"), None, None, None) => {
+                let store = self.store.clone();
+                parse_json_and!(create_bot, store)
+            },
             (Some("bots"), Some(id), Some("messages"), None) => {
                 let pool = self.pool.clone();
                 let handler = self.handler.clone();
                 let bot_id = String::from(id);
                 let bot_data = self.bot_data.clone();
                 let sender = self.event_loop_sender.clone();
+                let store = self.store.clone();
                 parse_json_and!(handle_events, pool, sender,
-                                bot_data, bot_id, handler)
+                                bot_data, store, bot_id, handler)
             },
             _ => parse_json_and!(empty_response, headers),
         }
@@ -137,9 +164,10 @@ fn empty_response(headers: Headers, data: SerdeValue,
     Ok(())
 }
 
-fn create_bot(data: BotCreationData, resp: &mut Response) -> BerylliumResult<()> {
+fn create_bot(store: Arc<BotStore>, data: BotCreationData,
+              resp: &mut Response) -> BerylliumResult<()> {
     info!("Creating new bot instance...");
-    let storage = StorageManager::new(data.id)?;
+    let storage = StorageManager::new(data.id, store)?;
     let mut prekeys = storage.initialize_prekeys(data.conversation.members.len())?;
     // There will always be a final prekey corresponding to u16::MAX
     let final_key = prekeys.pop().unwrap();
@@ -156,9 +184,182 @@ fn create_bot(data: BotCreationData, resp: &mut Response) -> BerylliumResult<()>
     Ok(())
 }
 
+/// Decrypt the actual message from the external data (sent along with the event)
+/// using the key in the given message.
+fn decrypt_external(message: &mut GenericMessage, data: Option<&String>)
+                   -> BerylliumResult<GenericMessage>
+{
+    info!("Decrypting external message...");
+    let external = message.take_external();
+    if external.get_encryption() != EncryptionAlgorithm::AES_CBC as i32 {
+        return Err(BerylliumError::Other(String::from("Unsupported external encryption")))
+    }
+
+    let bytes = match data {
+        Some(d) => base64::decode(d)?,
+        None => return Err(BerylliumError::Other(String::from("Missing external data"))),
+    };
+
+    let plain_bytes = utils::decrypt(&bytes, external.get_otr_key(), external.get_sha256())?;
+    Ok(protobuf::parse_from_bytes(&plain_bytes)?)
+}
+
+/// If this is an ephemeral message, then move its content into the message
+/// itself, and return the time after which the message should expire.
+fn unwrap_ephemeral(message: &mut GenericMessage) -> Option<Duration> {
+    if !message.has_ephemeral() {
+        return None
+    }
+
+    let mut ephemeral = message.take_ephemeral();
+    if ephemeral.has_text() {
+        message.set_text(ephemeral.take_text());
+    } else if ephemeral.has_asset() {
+        message.set_asset(ephemeral.take_asset());
+    } else if ephemeral.has_knock() {
+        message.set_knock(ephemeral.take_knock());
+    } else if ephemeral.has_location() {
+        message.set_location(ephemeral.take_location());
+    }
+
+    let millis = cmp::max(ephemeral.get_expire_after_millis(), 0) as u64;
+    Some(Duration::from_millis(millis))
+}
+
+/// Check whether the sender of this message wants to know when it's been read.
+fn expects_read_confirmation(message: &GenericMessage) -> bool {
+    if message.has_text() {
+        message.get_text().get_expects_read_confirmation()
+    } else if message.has_asset() {
+        message.get_asset().get_expects_read_confirmation()
+    } else if message.has_knock() {
+        message.get_knock().get_expects_read_confirmation()
+    } else if message.has_location() {
+        message.get_location().get_expects_read_confirmation()
+    } else if message.has_composite() {
+        message.get_composite().get_expects_read_confirmation()
+    } else {
+        false
+    }
+}
+
+/// Get the user event (if any) corresponding to a decrypted message.
+fn event_from_message(from: &str, mut message: GenericMessage) -> Option<Event> {
+    if message.has_text() {
+        info!("Got text message.");
+        let mut text = message.take_text();
+        let mentions = text.take_mentions().into_vec().into_iter()
+                           .filter_map(Mention::from_proto).collect();
+        let link_previews = text.take_link_preview().into_vec().into_iter()
+                                .map(LinkPreview::from).collect();
+        let quote = if text.has_quote() { Some(Quote::from(text.take_quote())) } else { None };
+        Some(Event::Message {
+            from: from.to_owned(),
+            text: text.take_content(),
+            mentions,
+            link_previews,
+            quote,
+        })
+    } else if message.has_asset() {
+        info!("Got asset message.");
+        Some(Event::Asset {
+            from: from.to_owned(),
+            asset: AssetInfo::from(message.take_asset()),
+        })
+    } else if message.has_edited() && message.get_edited().has_text() {
+        info!("Got edited message.");
+        let mut edit = message.take_edited();
+        Some(Event::MessageEdited {
+            from: from.to_owned(),
+            replacing_message_id: edit.take_replacing_message_id(),
+            text: edit.take_text().take_content(),
+        })
+    } else if message.has_edited() {
+        // Edits of composite messages (or unknown content) aren't supported yet.
+        info!("Ignoring edited message without text.");
+        None
+    } else if message.has_deleted() {
+        info!("Got deleted message.");
+        Some(Event::MessageDeleted {
+            from: from.to_owned(),
+            message_id: message.take_deleted().take_message_id(),
+        })
+    } else if message.has_hidden() {
+        info!("Got hidden message.");
+        let mut hide = message.take_hidden();
+        Some(Event::MessageHidden {
+            from: from.to_owned(),
+            conversation_id: hide.take_conversation_id(),
+            message_id: hide.take_message_id(),
+        })
+    } else if message.has_knock() {
+        info!("Got knock.");
+        Some(Event::Knock {
+            from: from.to_owned(),
+            hot: message.get_knock().get_hot_knock(),
+        })
+    } else if message.has_location() {
+        info!("Got location.");
+        Some(Event::Location {
+            from: from.to_owned(),
+            location: Location::from(message.take_location()),
+        })
+    } else if message.has_confirmation() {
+        info!("Got confirmation.");
+        let mut confirmation = message.take_confirmation();
+        let kind = match Confirmation_Type::from_i32(confirmation.get_field_type()) {
+            Some(Confirmation_Type::DELIVERED) => ConfirmationType::Delivered,
+            Some(Confirmation_Type::READ) => ConfirmationType::Read,
+            None => {
+                info!("Ignoring confirmation of unknown type {}", confirmation.get_field_type());
+                return None
+            },
+        };
+
+        Some(Event::Confirmation {
+            from: from.to_owned(),
+            kind,
+            message_id: confirmation.take_first_message_id(),
+            more_message_ids: confirmation.take_more_message_ids().into_vec(),
+        })
+    } else if message.has_reaction() {
+        info!("Got reaction.");
+        let mut reaction = message.take_reaction();
+        Some(Event::Reaction {
+            from: from.to_owned(),
+            message_id: reaction.take_message_id(),
+            emoji: reaction.take_emoji(),
+        })
+    } else if message.has_buttonAction() {
+        info!("Got button action.");
+        let mut action = message.take_buttonAction();
+        Some(Event::ButtonAction {
+            from: from.to_owned(),
+            reference_message_id: action.take_reference_message_id(),
+            button_id: action.take_button_id(),
+        })
+    } else if message.has_availability() {
+        info!("Got availability status.");
+        Some(Event::Availability {
+            from: from.to_owned(),
+            status: AvailabilityStatus::from_proto(message.get_availability().get_field_type()),
+        })
+    } else if message.has_composite() {
+        info!("Got composite message.");
+        Some(Event::Composite {
+            from: from.to_owned(),
+            items: message.take_composite().take_items().into_vec().into_iter()
+                          .filter_map(CompositeItem::from_proto).collect(),
+        })
+    } else {        // FIXME: Handle other message types
+        info!("Ignoring unsupported message.");
+        None
+    }
+}
+
 fn handle_events<H>(pool: Arc<CpuPool>, job_sender: FutureSender<EventLoopRequest<()>>,
                     bot_data: Arc<Mutex<HashMap<Uuid, Arc<Mutex<BotData>>>>>,
-                    bot_id: String, handler: Arc<H>,
+                    store: Arc<BotStore>, bot_id: String, handler: Arc<H>,
                     data: MessageData, resp: &mut Response)
                    -> BerylliumResult<()>
     where H: Handler
@@ -170,13 +371,15 @@ fn handle_events<H>(pool: Arc<CpuPool>, job_sender: FutureSender<EventLoopReques
 
     // Maybe this is the first time we're getting events, or we've rebooted
     // our bot and we don't have the creation data in memory.
-    let this_bot_data = if bot_data.lock().get(&bot_id).is_none() {
-        let this_bot_data = BotData::from_storage(bot_id)?;
-        let this_bot_data = Arc::new(Mutex::new(this_bot_data));
-        bot_data.lock().insert(bot_id, this_bot_data.clone());
-        this_bot_data
-    } else {
-        bot_data.lock().get(&bot_id).unwrap().clone()
+    // We hold the lock while loading, so that concurrent requests share the same data.
+    let this_bot_data = {
+        let mut bot_data = bot_data.lock();
+        if !bot_data.contains_key(&bot_id) {
+            let this_bot_data = BotData::from_storage(bot_id, store)?;
+            bot_data.insert(bot_id, Arc::new(Mutex::new(this_bot_data)));
+        }
+
+        bot_data[&bot_id].clone()
     };
 
     // NOTE: Since we have `Arc<Mutex<BotData>>`, we won't block the
@@ -185,7 +388,8 @@ fn handle_events<H>(pool: Arc<CpuPool>, job_sender: FutureSender<EventLoopReques
 
     match (data.type_, &data.data) {
         (ConversationEventType::MessageAdd,
-         &ConversationData::MessageAdd { ref sender, recipient: _, ref text }) => {
+         &ConversationData::MessageAdd { ref sender, recipient: _, ref text,
+                                         data: ref external_data }) => {
             let (storage, client, devices) = {
                 let lock = this_bot_data.lock();
                 (lock.storage.clone(), lock.client.clone(), lock.devices.clone())
@@ -193,29 +397,52 @@ fn handle_events<H>(pool: Arc<CpuPool>, job_sender: FutureSender<EventLoopReques
 
             let plain_bytes = storage.decrypt(&data.from, sender, text)?;
             let mut message: GenericMessage = protobuf::parse_from_bytes(&plain_bytes)?;
+            if message.has_external() {
+                message = decrypt_external(&mut message, external_data.as_ref())?;
+            }
+
             info!("Successfully decrypted message!");
 
             // We can decrypt and decode the message - 200 OK
             let msg_id = message.get_message_id().to_owned();
-            // Async queue confirmation into event loop.
-            job_sender.clone().send(Box::new(move |c: &HyperClient| {
-                client.send_confirmation(c, &msg_id, storage.clone(), devices.clone())
-            })).wait().map_err(|e| {
-                error!("Cannot queue confirmation message in event loop: {}", e);
-            }).ok();
-
-            if message.has_text() {     // FIXME: Handle other message types
-                info!("Got text message.");
-                let mut text = message.take_text();
-                let content = text.take_content();
+            let confirm_id = msg_id.clone();
+            // Async queue confirmation into event loop (but, we don't
+            // confirm confirmations, since that would never end).
+            if !message.has_confirmation() {
+                let (client, storage, devices) = (client.clone(), storage.clone(), devices.clone());
+                job_sender.clone().send(Box::new(move |c: &HyperClient| {
+                    client.send_confirmation(c, &confirm_id, storage.clone(), devices.clone())
+                })).wait().map_err(|e| {
+                    error!("Cannot queue confirmation message in event loop: {}", e);
+                }).ok();
+            }
+
+            // Button clicks are acknowledged only to the user who clicked.
+            if message.has_buttonAction() {
+                let action = message.get_buttonAction();
+                let (ref_id, button_id) = (action.get_reference_message_id().to_owned(),
+                                           action.get_button_id().to_owned());
+                let user_id = data.from.clone();
+                job_sender.clone().send(Box::new(move |c: &HyperClient| {
+                    client.send_button_action_confirmation(c, &ref_id, &button_id, &user_id,
+                                                           storage.clone(), devices.clone())
+                })).wait().map_err(|e| {
+                    error!("Cannot queue button action confirmation in event loop: {}", e);
+                }).ok();
+            }
 
+            let expires_after = unwrap_ephemeral(&mut message);
+            let expects_read_confirmation = expects_read_confirmation(&message);
+            if let Some(event) = event_from_message(&data.from, message) {
                 event_occurred = Some(EventData {
                     bot_id,
                     conversation: this_bot_data.lock().data.conversation.clone(),
-                    event: Event::Message {
-                        from: data.from.clone(),
-                        text: content,
-                    }
+                    message_id: Some(msg_id),
+                    sender_client: Some(sender.clone()),
+                    time: data.time,
+                    expires_after,
+                    expects_read_confirmation,
+                    event,
                 });
             }
         },
@@ -233,6 +460,9 @@ fn handle_events<H>(pool: Arc<CpuPool>, job_sender: FutureSender<EventLoopReques
                     });
                 }
 
+                // Persist the changes (while we're holding the lock), so that
+                // the bot knows about them when it's restarted.
+                old_data.save_state()?;
                 old_data.data.conversation.clone()
             };
 
@@ -242,6 +472,11 @@ fn handle_events<H>(pool: Arc<CpuPool>, job_sender: FutureSender<EventLoopReques
             event_occurred = Some(EventData {
                 bot_id,
                 conversation,
+                message_id: None,
+                sender_client: None,
+                time: data.time,
+                expires_after: None,
+                expects_read_confirmation: false,
                 event: Event::ConversationMemberJoin { members_joined },
             });
         },
@@ -250,11 +485,19 @@ fn handle_events<H>(pool: Arc<CpuPool>, job_sender: FutureSender<EventLoopReques
          &ConversationData::LeavingOrJoiningMembers { ref user_ids }) => {
             let conversation = {
                 let mut old_data = this_bot_data.lock();
-                // Remove users from existing data.
-                for id in user_ids {
-                    old_data.data.conversation.members.remove(id);
+                // Remove users (and their devices) from existing data.
+                {
+                    let devices = old_data.devices.clone();
+                    let mut devices = devices.lock();
+                    for id in user_ids {
+                        old_data.data.conversation.members.remove(id);
+                        devices.missing.remove(&id.to_string());
+                    }
+
+                    old_data.storage.save_devices(&devices.missing)?;
                 }
 
+                old_data.save_state()?;
                 old_data.data.conversation.clone()
             };
 
@@ -269,6 +512,11 @@ fn handle_events<H>(pool: Arc<CpuPool>, job_sender: FutureSender<EventLoopReques
             event_occurred = Some(EventData {
                 bot_id,
                 conversation,
+                message_id: None,
+                sender_client: None,
+                time: data.time,
+                expires_after: None,
+                expects_read_confirmation: false,
                 event: Event::ConversationMemberLeave { members_left },
             });
         },
@@ -280,12 +528,18 @@ fn handle_events<H>(pool: Arc<CpuPool>, job_sender: FutureSender<EventLoopReques
                 info!("conversation {} has been renamed from {} to {}",
                       old_data.data.conversation.id, old_data.data.conversation.name, name);
                 old_data.data.conversation.name = name.clone();
+                old_data.save_state()?;
                 old_data.data.conversation.clone()
             };
 
             event_occurred = Some(EventData {
                 bot_id,
                 conversation,
+                message_id: None,
+                sender_client: None,
+                time: data.time,
+                expires_after: None,
+                expects_read_confirmation: false,
                 event: Event::ConversationRename,
             });
         },
@@ -317,3 +571,69 @@ fn handle_events<H>(pool: Arc<CpuPool>, job_sender: FutureSender<EventLoopReques
     resp.set_status(StatusCode::Ok);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use messages_proto::{Availability, Confirmation, Text};
+    use protobuf::Message as ProtobufMessage;
+
+    /// Encode the message and decode it again (like we do for incoming messages).
+    fn reencode(message: &GenericMessage) -> GenericMessage {
+        let bytes = message.write_to_bytes().unwrap();
+        protobuf::parse_from_bytes(&bytes).unwrap()
+    }
+
+    fn new_message() -> GenericMessage {
+        let mut message = GenericMessage::new();
+        message.set_message_id(String::from("a3d6c5d4-5b7e-11e8-9c2d-fa7ae01bbebc"));
+        message
+    }
+
+    #[test]
+    fn test_unknown_legal_hold_status() {
+        let mut message = new_message();
+        let mut text = Text::new();
+        text.set_content(String::from("Hello!"));
+        text.set_legal_hold_status(42);
+        message.set_text(text);
+        match event_from_message("user", reencode(&message)) {
+            Some(Event::Message { text, .. }) => assert_eq!(text, "Hello!"),
+            _ => panic!("Expected a text message"),
+        }
+    }
+
+    #[test]
+    fn test_unknown_availability() {
+        let mut message = new_message();
+        let mut availability = Availability::new();
+        availability.set_field_type(7);
+        message.set_availability(availability);
+        match event_from_message("user", reencode(&message)) {
+            Some(Event::Availability { status, .. }) => {
+                assert_eq!(status, AvailabilityStatus::None)
+            },
+            _ => panic!("Expected an availability status"),
+        }
+    }
+
+    #[test]
+    fn test_unknown_confirmation() {
+        let mut message = new_message();
+        let mut confirmation = Confirmation::new();
+        confirmation.set_first_message_id(String::from("b0b5c2b0-5b7e-11e8-9c2d-fa7ae01bbebc"));
+        confirmation.set_field_type(5);
+        message.set_confirmation(confirmation);
+        assert!(event_from_message("user", reencode(&message)).is_none());
+
+        let mut message = new_message();
+        let mut confirmation = Confirmation::new();
+        confirmation.set_first_message_id(String::from("b0b5c2b0-5b7e-11e8-9c2d-fa7ae01bbebc"));
+        confirmation.set_field_type(Confirmation_Type::READ as i32);
+        message.set_confirmation(confirmation);
+        match event_from_message("user", reencode(&message)) {
+            Some(Event::Confirmation { kind: ConfirmationType::Read, .. }) => (),
+            _ => panic!("Expected a read confirmation"),
+        }
+    }
+}
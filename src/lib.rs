@@ -1,4 +1,5 @@
 extern crate base64;
+extern crate chrono;
 extern crate cryptobox;
 extern crate futures;
 extern crate futures_cpupool;
@@ -14,6 +15,8 @@ extern crate parking_lot;
 extern crate proteus;
 extern crate protobuf;
 extern crate rustls;
+#[cfg(feature = "sqlite")]
+#[macro_use] extern crate rusqlite;
 extern crate serde;
 #[macro_use] extern crate serde_derive;
 extern crate serde_json;
@@ -34,8 +37,17 @@ mod types;
 include!(concat!(env!("OUT_DIR"), "/messages.rs"));
 
 pub mod errors;
+#[cfg(feature = "polls")]
+pub mod polls;
 
 pub use client::BotClient;
 pub use handlers::Handler;
+pub use mime::Mime;
 pub use service::BotService;
-pub use types::{Event, EventData, Image, ImageFormat};
+pub use storage::{BotStore, EncryptedStore, FileStore, MasterKey, MemoryStore, copy_bots};
+#[cfg(feature = "sqlite")]
+pub use storage::SqliteStore;
+pub use types::{AssetInfo, AssetMetaData, AssetOriginal, Audio, AudioMeta, ConfirmationType};
+pub use types::{AvailabilityStatus, CompositeItem, Event, EventData, Image, ImageFormat};
+pub use types::{Location, Mention, RemoteAsset};
+pub use types::{CompositeMessage, LinkPreview, Quote, RichText, Tweet, Video, VideoMeta};
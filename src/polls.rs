@@ -0,0 +1,348 @@
+//! Polls built on top of composite messages.
+//!
+//! A poll is posted as a message with the question, the current results and
+//! a button for each option (along with a button to close the poll). Users vote
+//! by clicking a button, or by reacting to the poll with a keycap emoji ("1️⃣",
+//! "2️⃣" and so on). Each user has a single vote, which can be changed anytime
+//! until the poll is closed. The message is edited with the live results on
+//! every vote, and the polls are persisted in the bot's storage.
+//!
+//! ```rust,ignore
+//! impl Handler for MyBot {
+//!     fn handle(&self, data: EventData, client: BotClient) {
+//!         let polls = Polls::new(client);
+//!         if let Ok(true) = polls.handle_event(&data) {
+//!             return      // this was a vote
+//!         }
+//!
+//!         if let Event::Message { ref text, ref from, .. } = data.event {
+//!             if text == "/lunch" {
+//!                 polls.create("Lunch?", &["Pizza", "Sushi"], Some(from.as_str())).ok();
+//!             }
+//!         }
+//!     }
+//! }
+//! ```
+//!
+//! Note that the calls in this module block until the poll message has been
+//! sent (or edited), so they should be made from the handler. Updates to the
+//! polls of a bot are made one after the other (with the bot's data lock held),
+//! so that no vote is lost, and each edit replaces the latest poll message.
+
+use client::BotClient;
+use errors::{BerylliumError, BerylliumResult};
+use futures::Future;
+use std::collections::HashMap;
+use types::{CompositeMessage, Event, EventData};
+
+/// Name of the bot data in which the polls are stored.
+const POLLS_DATA: &'static str = "polls";
+const OPTION_BUTTON_PREFIX: &'static str = "poll-option-";
+const CLOSE_BUTTON_ID: &'static str = "poll-close";
+/// There are only nine keycap emoji ("1️⃣" to "9️⃣") for voting with reactions.
+const MAX_OPTIONS: usize = 9;
+
+type PollMap = HashMap<String, Poll>;
+
+/// A poll and its votes.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Poll {
+    pub question: String,
+    pub options: Vec<String>,
+    /// Index of the option chosen by each user.
+    pub votes: HashMap<String, usize>,
+    pub closed: bool,
+    /// User who can close the poll (if there's no owner, anyone can close it).
+    pub owner: Option<String>,
+    /// ID of the poll (i.e., the ID of the message with which it was posted).
+    id: String,
+    /// ID of the latest poll message (it gets a new ID whenever it's edited).
+    message_id: String,
+}
+
+impl Poll {
+    /// ID of the poll (which doesn't change when the poll message is edited).
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Number of votes for each option.
+    pub fn tally(&self) -> Vec<usize> {
+        let mut counts = vec![0; self.options.len()];
+        for &idx in self.votes.values() {
+            if idx < counts.len() {
+                counts[idx] += 1;
+            }
+        }
+
+        counts
+    }
+
+    /// Build the poll message (with the current results).
+    fn render(&self) -> CompositeMessage {
+        let mut text = self.question.clone();
+        if self.closed {
+            text.push_str(" (closed)");
+        }
+
+        for (i, (option, count)) in self.options.iter().zip(self.tally()).enumerate() {
+            text.push_str(&format!("\n{}. {} - {} vote{}", i + 1, option, count,
+                                   if count == 1 { "" } else { "s" }));
+        }
+
+        let mut message = CompositeMessage::new().text(&text);
+        if !self.closed {
+            for (i, option) in self.options.iter().enumerate() {
+                let id = format!("{}{}", OPTION_BUTTON_PREFIX, i);
+                message = message.button(&id, option);
+            }
+
+            message = message.button(CLOSE_BUTTON_ID, "Close poll");
+        }
+
+        message
+    }
+}
+
+/// What a user has done to a poll.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Action {
+    /// Vote for the option (index).
+    Vote(usize),
+    /// Take back the vote.
+    Retract,
+    /// Close the poll.
+    Close,
+}
+
+/// Get the option (index) for a keycap emoji reaction ("1️⃣" to "9️⃣").
+fn option_from_emoji(emoji: &str) -> Option<usize> {
+    let mut chars = emoji.chars().filter(|&c| c != '\u{fe0f}');
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some(d @ '1'..='9'), Some('\u{20e3}'), None) => Some(d as usize - '1' as usize),
+        _ => None,
+    }
+}
+
+/// Get the poll action (if any) in the given event, along with the ID
+/// of the message it refers to and the user who did it.
+fn action_from_event(event: &Event) -> Option<(&str, &str, Action)> {
+    match *event {
+        Event::ButtonAction { ref reference_message_id, ref button_id, ref from } => {
+            let action = if button_id == CLOSE_BUTTON_ID {
+                Action::Close
+            } else if button_id.starts_with(OPTION_BUTTON_PREFIX) {
+                match button_id[OPTION_BUTTON_PREFIX.len()..].parse() {
+                    Ok(idx) => Action::Vote(idx),
+                    Err(_) => return None,
+                }
+            } else {
+                return None
+            };
+
+            Some((reference_message_id.as_str(), from.as_str(), action))
+        },
+        Event::Reaction { ref message_id, ref emoji, ref from } => {
+            // An empty emoji (i.e., removed reaction) takes back the vote.
+            let action = if emoji.is_empty() {
+                Action::Retract
+            } else {
+                match option_from_emoji(emoji) {
+                    Some(idx) => Action::Vote(idx),
+                    None => return None,
+                }
+            };
+
+            Some((message_id.as_str(), from.as_str(), action))
+        },
+        _ => None,
+    }
+}
+
+/// Apply the action of the user to the poll. Returns `false` if nothing's changed.
+fn apply(poll: &mut Poll, from: &str, action: Action) -> bool {
+    if poll.closed {
+        info!("Ignoring action on closed poll {}", poll.id);
+        return false
+    }
+
+    match action {
+        Action::Close => {
+            if poll.owner.as_ref().map(|o| o == from).unwrap_or(true) {
+                info!("Closing poll {}", poll.id);
+                poll.closed = true;
+                true
+            } else {
+                info!("{} cannot close poll {}", from, poll.id);
+                false
+            }
+        },
+        Action::Vote(idx) if idx < poll.options.len() => {
+            poll.votes.insert(from.to_owned(), idx) != Some(idx)
+        },
+        Action::Vote(_) => false,
+        Action::Retract => poll.votes.remove(from).is_some(),
+    }
+}
+
+/// Polls of a bot instance.
+pub struct Polls {
+    client: BotClient,
+}
+
+impl Polls {
+    pub fn new(client: BotClient) -> Polls {
+        Polls { client }
+    }
+
+    fn load(&self) -> BerylliumResult<PollMap> {
+        Ok(self.client.load_data(POLLS_DATA)?.unwrap_or_default())
+    }
+
+    /// Post a new poll to the conversation and return its ID.
+    pub fn create(&self, question: &str, options: &[&str],
+                  owner: Option<&str>) -> BerylliumResult<String> {
+        if options.len() < 2 || options.len() > MAX_OPTIONS {
+            return Err(BerylliumError::Other(format!(
+                "A poll needs two to {} options (found {})", MAX_OPTIONS, options.len())))
+        }
+
+        let mut poll = Poll {
+            question: question.to_owned(),
+            options: options.iter().map(|&s| s.to_owned()).collect(),
+            votes: HashMap::new(),
+            closed: false,
+            owner: owner.map(String::from),
+            id: String::new(),
+            message_id: String::new(),
+        };
+
+        let id = self.client.send_composite(poll.render()).wait()?;
+        info!("Created poll {}", id);
+        poll.id = id.clone();
+        poll.message_id = id.clone();
+
+        self.client.with_data_lock(|| -> BerylliumResult<()> {
+            let mut polls = self.load()?;
+            polls.insert(id.clone(), poll);
+            self.client.save_data(POLLS_DATA, &polls)
+        })?;
+
+        Ok(id)
+    }
+
+    /// Get the poll with the given ID (if any).
+    pub fn get(&self, poll_id: &str) -> BerylliumResult<Option<Poll>> {
+        Ok(self.load()?.remove(poll_id))
+    }
+
+    /// Close the poll, so that it doesn't accept votes anymore.
+    pub fn close(&self, poll_id: &str) -> BerylliumResult<()> {
+        self.client.with_data_lock(|| -> BerylliumResult<()> {
+            let mut polls = self.load()?;
+            match polls.get_mut(poll_id) {
+                Some(ref poll) if poll.closed => return Ok(()),
+                Some(poll) => poll.closed = true,
+                None => return Err(BerylliumError::Other(format!("Unknown poll {}", poll_id))),
+            }
+
+            self.client.save_data(POLLS_DATA, &polls)?;
+            self.refresh(&mut polls, poll_id)
+        })
+    }
+
+    /// Edit the poll message to show the current state of the poll, and save its
+    /// new message ID. This should be called with the data lock held, so that
+    /// the next update edits the message that we've just sent.
+    fn refresh(&self, polls: &mut PollMap, poll_id: &str) -> BerylliumResult<()> {
+        {
+            let poll = match polls.get_mut(poll_id) {
+                Some(p) => p,
+                None => return Ok(()),
+            };
+
+            let new_id = self.client.edit_composite(&poll.message_id, poll.render()).wait()?;
+            poll.message_id = new_id;
+        }
+
+        self.client.save_data(POLLS_DATA, &*polls)
+    }
+
+    /// Record the vote (or the close action) in the given event. Returns `false`
+    /// if the event has nothing to do with the polls of this bot.
+    pub fn handle_event(&self, data: &EventData) -> BerylliumResult<bool> {
+        let (message_id, from, action) = match action_from_event(&data.event) {
+            Some(a) => a,
+            None => return Ok(false),
+        };
+
+        self.client.with_data_lock(|| -> BerylliumResult<bool> {
+            let mut polls = self.load()?;
+            let poll_id = match polls.values_mut().find(|p| p.message_id == message_id) {
+                Some(poll) => {
+                    if !apply(poll, from, action) {
+                        return Ok(true)
+                    }
+
+                    poll.id.clone()
+                },
+                None => return Ok(false),
+            };
+
+            // Save the vote first, so that it's not lost if we can't edit the message.
+            self.client.save_data(POLLS_DATA, &polls)?;
+            self.refresh(&mut polls, &poll_id)?;
+            Ok(true)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn poll(owner: Option<&str>) -> Poll {
+        Poll {
+            question: String::from("Lunch?"),
+            options: vec![String::from("Pizza"), String::from("Sushi")],
+            votes: HashMap::new(),
+            closed: false,
+            owner: owner.map(String::from),
+            id: String::from("poll"),
+            message_id: String::from("poll"),
+        }
+    }
+
+    #[test]
+    fn test_option_from_emoji() {
+        assert_eq!(option_from_emoji("1\u{fe0f}\u{20e3}"), Some(0));
+        assert_eq!(option_from_emoji("9\u{20e3}"), Some(8));
+        assert_eq!(option_from_emoji("0\u{fe0f}\u{20e3}"), None);
+        assert_eq!(option_from_emoji("\u{2764}\u{fe0f}"), None);
+        assert_eq!(option_from_emoji(""), None);
+    }
+
+    #[test]
+    fn test_votes() {
+        let mut poll = poll(None);
+        assert!(apply(&mut poll, "alice", Action::Vote(0)));
+        assert!(!apply(&mut poll, "alice", Action::Vote(0)));
+        assert!(apply(&mut poll, "alice", Action::Vote(1)));
+        assert!(apply(&mut poll, "bob", Action::Vote(1)));
+        assert!(!apply(&mut poll, "bob", Action::Vote(2)));
+        assert_eq!(poll.tally(), vec![0, 2]);
+        assert!(apply(&mut poll, "bob", Action::Retract));
+        assert!(!apply(&mut poll, "bob", Action::Retract));
+        assert_eq!(poll.tally(), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_close() {
+        let mut poll = poll(Some("alice"));
+        assert!(!apply(&mut poll, "bob", Action::Close));
+        assert!(apply(&mut poll, "alice", Action::Close));
+        assert!(poll.closed);
+        assert!(!apply(&mut poll, "bob", Action::Vote(0)));
+        assert!(poll.votes.is_empty());
+    }
+}
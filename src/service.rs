@@ -1,4 +1,4 @@
-use {openssl, utils};
+use {openssl, proteus, utils};
 use errors::{BerylliumError, BerylliumResult};
 use futures::{Future, Stream};
 use futures::sync::mpsc as futures_mpsc;
@@ -14,6 +14,7 @@ use std::net::SocketAddr;
 use std::path::Path;
 use std::sync::Arc;
 use std::thread;
+use storage::{BotStore, FileStore};
 use tokio_core::reactor::Core;
 use tokio_rustls::proto::Server;
 use tokio_proto::TcpServer;
@@ -21,6 +22,7 @@ use types::EventLoopRequest;
 
 pub struct BotService {
     config: ServerConfig,
+    store: Arc<BotStore>,
 }
 
 impl BotService {
@@ -57,18 +59,28 @@ impl BotService {
     pub fn new<P>(auth: String, store_path: P, key_path: P, cert_path: P)
                   -> BerylliumResult<BotService>
         where P: AsRef<Path>
+    {
+        Self::with_store(auth, FileStore::new(store_path), key_path, cert_path)
+    }
+
+    /// Same as `new`, but the bot data is kept in the given storage backend
+    /// (instead of files in some directory).
+    pub fn with_store<S, P>(auth: String, store: S, key_path: P, cert_path: P)
+                            -> BerylliumResult<BotService>
+        where S: BotStore, P: AsRef<Path>
     {
         openssl::init();
+        proteus::init();
         let certs = Self::load_certs(cert_path)?;
         let key = Self::load_private_key(key_path)?;
         // We don't need client auth, because we're checking `Authorization` header.
         let mut tls_config = ServerConfig::new(NoClientAuth::new());
         tls_config.set_single_cert(certs, key);
         utils::set_auth_token(auth);
-        utils::set_store_path(store_path);
 
         Ok(BotService {
             config: tls_config,
+            store: Arc::new(store),
         })
     }
 
@@ -84,7 +96,7 @@ impl BotService {
         let https_server = Server::new(Http::new(), Arc::new(self.config));
         let tcp_server = TcpServer::new(https_server, addr.clone());
         let (tx, rx) = futures_mpsc::channel(0);
-        let handler = Arc::new(handler);
+        let bot_handler = BotHandler::new(Arc::new(handler), tx, self.store);
 
         let _ = thread::spawn(move || {
             let mut core = Core::new().expect("event loop creation");
@@ -103,7 +115,7 @@ impl BotService {
         });
 
         tcp_server.serve(move || {
-            Ok(BotHandler::new(handler.clone(), tx.clone()))
+            Ok(bot_handler.clone())
         });
     }
 }
@@ -0,0 +1,485 @@
+use base64;
+use errors::{BerylliumError, BerylliumResult};
+use openssl::rand;
+use openssl::symm::{self, Cipher};
+use sha2::{Sha256, Digest};
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use super::{BotStore, load_any_identity};
+use uuid::Uuid;
+
+// Blob layout: magic (4 bytes) + version (1) + key ID (4) + nonce (12)
+//              + ciphertext + tag (16)
+const MAGIC: &'static [u8] = b"BEnc";
+const VERSION: u8 = 1;
+const KEY_ID_LEN: usize = 4;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const HEADER_LEN: usize = 4 + 1 + KEY_ID_LEN + NONCE_LEN;
+
+/// 256-bit key used for encrypting the stored data.
+#[derive(Clone)]
+pub struct MasterKey {
+    key: Vec<u8>,
+    id: Vec<u8>,
+}
+
+// Never print the key.
+impl fmt::Debug for MasterKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MasterKey({})", base64::encode(&self.id))
+    }
+}
+
+impl MasterKey {
+    /// Create a key from its raw bytes (which must be 32 bytes long).
+    pub fn from_bytes(bytes: &[u8]) -> BerylliumResult<MasterKey> {
+        if bytes.len() != Cipher::aes_256_gcm().key_len() {
+            return Err(BerylliumError::Other(format!(
+                "Master key should be 32 bytes long (found {} bytes)", bytes.len())))
+        }
+
+        let digest = Sha256::digest(bytes);
+        Ok(MasterKey {
+            key: bytes.to_owned(),
+            id: digest[..KEY_ID_LEN].to_owned(),
+        })
+    }
+
+    /// Create a key from its base64 representation.
+    pub fn from_base64(encoded: &str) -> BerylliumResult<MasterKey> {
+        let bytes = base64::decode(encoded.trim())?;
+        MasterKey::from_bytes(&bytes)
+    }
+
+    /// Read the (base64 encoded) key from the given environment variable.
+    pub fn from_env(var: &str) -> BerylliumResult<MasterKey> {
+        let encoded = env::var(var).map_err(|e| {
+            BerylliumError::Other(format!("Cannot read master key from {}: {}", var, e))
+        })?;
+
+        MasterKey::from_base64(&encoded)
+    }
+
+    /// Read the (base64 encoded) key from the given file.
+    pub fn from_file<P>(path: P) -> BerylliumResult<MasterKey>
+        where P: AsRef<Path>
+    {
+        let mut encoded = String::new();
+        File::open(path)?.read_to_string(&mut encoded)?;
+        MasterKey::from_base64(&encoded)
+    }
+
+    /// Generate a new random key.
+    pub fn generate() -> BerylliumResult<MasterKey> {
+        let mut bytes = vec![0; Cipher::aes_256_gcm().key_len()];
+        rand::rand_bytes(&mut bytes)?;
+        MasterKey::from_bytes(&bytes)
+    }
+
+    /// Base64 representation of this key (for saving it somewhere).
+    pub fn to_base64(&self) -> String {
+        base64::encode(&self.key)
+    }
+}
+
+/// Wraps another store and encrypts everything (state, devices, data,
+/// identity, prekeys and sessions) with AES-256-GCM before it reaches that store.
+///
+/// Unencrypted data is rejected, unless it's explicitly allowed (while
+/// encrypting an existing store for the first time).
+pub struct EncryptedStore<S> {
+    inner: S,
+    key: MasterKey,
+    old_keys: Vec<MasterKey>,
+    allow_plaintext: bool,
+}
+
+impl<S: BotStore> EncryptedStore<S> {
+    pub fn new(inner: S, key: MasterKey) -> EncryptedStore<S> {
+        EncryptedStore {
+            inner: inner,
+            key: key,
+            old_keys: vec![],
+            allow_plaintext: false,
+        }
+    }
+
+    /// Add a previous master key, so that the data encrypted with it can still
+    /// be read (while rotating the keys).
+    pub fn with_old_key(mut self, key: MasterKey) -> EncryptedStore<S> {
+        self.old_keys.push(key);
+        self
+    }
+
+    /// Also read the data which was written before enabling encryption (as
+    /// plaintext). It gets encrypted the next time it's saved (or on `rotate`).
+    pub fn allow_plaintext(mut self) -> EncryptedStore<S> {
+        self.allow_plaintext = true;
+        self
+    }
+
+    /// Re-encrypt the data of all bots with the current master key, and remove
+    /// whatever the older versions of the store have left behind (like the
+    /// cryptobox identity). This can be safely re-run if it's interrupted.
+    /// Returns the number of bots processed.
+    pub fn rotate(&self) -> BerylliumResult<usize> {
+        let bots = self.inner.list_bots()?;
+        for bot_id in &bots {
+            info!("Re-encrypting data of bot {}", bot_id);
+            if let Some(bytes) = self.load_state(bot_id)? {
+                self.save_state(bot_id, &bytes)?;
+            }
+
+            if let Some(bytes) = self.load_devices(bot_id)? {
+                self.save_devices(bot_id, &bytes)?;
+            }
+
+            if let Some(bytes) = load_any_identity(self, bot_id)? {
+                self.save_identity(bot_id, &bytes)?;
+            }
+
+            for name in self.inner.list_data(bot_id)? {
+                if let Some(bytes) = self.load_data(bot_id, &name)? {
+                    self.save_data(bot_id, &name, &bytes)?;
+                }
+            }
+
+            for id in self.inner.list_prekeys(bot_id)? {
+                if let Some(bytes) = self.load_prekey(bot_id, id)? {
+                    self.save_prekey(bot_id, id, &bytes)?;
+                }
+            }
+
+            for id in self.inner.list_sessions(bot_id)? {
+                if let Some(bytes) = self.load_session(bot_id, &id)? {
+                    self.save_session(bot_id, &id, &bytes)?;
+                }
+            }
+
+            self.remove_legacy(bot_id)?;
+        }
+
+        Ok(bots.len())
+    }
+
+    /// The additional authenticated data binds a blob to its bot and its place
+    /// in the store, so that blobs can't be swapped around.
+    fn aad(bot_id: &Uuid, kind: &str) -> Vec<u8> {
+        format!("{}/{}", bot_id, kind).into_bytes()
+    }
+
+    fn encrypt(&self, bot_id: &Uuid, kind: &str, data: &[u8]) -> BerylliumResult<Vec<u8>> {
+        let mut nonce = vec![0; NONCE_LEN];
+        rand::rand_bytes(&mut nonce)?;
+        let mut tag = vec![0; TAG_LEN];
+        let mut bytes = symm::encrypt_aead(Cipher::aes_256_gcm(), &self.key.key, Some(&nonce),
+                                           &Self::aad(bot_id, kind), data, &mut tag)?;
+
+        let mut out = Vec::with_capacity(HEADER_LEN + bytes.len() + TAG_LEN);
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        out.extend_from_slice(&self.key.id);
+        out.extend_from_slice(&nonce);
+        out.append(&mut bytes);
+        out.extend_from_slice(&tag);
+        Ok(out)
+    }
+
+    fn decrypt(&self, bot_id: &Uuid, kind: &str, data: Option<Vec<u8>>)
+              -> BerylliumResult<Option<Vec<u8>>>
+    {
+        let data = match data {
+            Some(d) => d,
+            None => return Ok(None),
+        };
+
+        if !data.starts_with(MAGIC) {
+            if self.allow_plaintext {
+                warn!("Found unencrypted {} for bot {}", kind, bot_id);
+                return Ok(Some(data))
+            }
+
+            return Err(BerylliumError::Other(format!(
+                "Found unencrypted {} for bot {} (plaintext isn't allowed)", kind, bot_id)))
+        }
+
+        if data.len() < HEADER_LEN + TAG_LEN || data[MAGIC.len()] != VERSION {
+            return Err(BerylliumError::Other(format!(
+                "Invalid encrypted {} for bot {}", kind, bot_id)))
+        }
+
+        let key_id = &data[MAGIC.len() + 1..MAGIC.len() + 1 + KEY_ID_LEN];
+        let key = match Some(&self.key).into_iter().chain(&self.old_keys)
+                                       .find(|k| k.id == key_id) {
+            Some(k) => k,
+            None => return Err(BerylliumError::Other(format!(
+                "No master key with ID {} for {} of bot {}", base64::encode(key_id), kind, bot_id))),
+        };
+
+        let nonce = &data[HEADER_LEN - NONCE_LEN..HEADER_LEN];
+        let (bytes, tag) = data[HEADER_LEN..].split_at(data.len() - HEADER_LEN - TAG_LEN);
+        let plain = symm::decrypt_aead(Cipher::aes_256_gcm(), &key.key, Some(nonce),
+                                       &Self::aad(bot_id, kind), bytes, tag).map_err(|_| {
+            BerylliumError::Other(format!("Cannot decrypt {} of bot {} (wrong key or tampered data)",
+                                          kind, bot_id))
+        })?;
+
+        Ok(Some(plain))
+    }
+}
+
+impl<S: BotStore> BotStore for EncryptedStore<S> {
+    fn load_state(&self, bot_id: &Uuid) -> BerylliumResult<Option<Vec<u8>>> {
+        let data = self.inner.load_state(bot_id)?;
+        self.decrypt(bot_id, "state", data)
+    }
+
+    fn save_state(&self, bot_id: &Uuid, data: &[u8]) -> BerylliumResult<()> {
+        let bytes = self.encrypt(bot_id, "state", data)?;
+        self.inner.save_state(bot_id, &bytes)
+    }
+
+    fn load_devices(&self, bot_id: &Uuid) -> BerylliumResult<Option<Vec<u8>>> {
+        let data = self.inner.load_devices(bot_id)?;
+        self.decrypt(bot_id, "devices", data)
+    }
+
+    fn save_devices(&self, bot_id: &Uuid, data: &[u8]) -> BerylliumResult<()> {
+        let bytes = self.encrypt(bot_id, "devices", data)?;
+        self.inner.save_devices(bot_id, &bytes)
+    }
+
+    fn load_data(&self, bot_id: &Uuid, name: &str) -> BerylliumResult<Option<Vec<u8>>> {
+        let data = self.inner.load_data(bot_id, name)?;
+        self.decrypt(bot_id, &format!("data/{}", name), data)
+    }
+
+    fn save_data(&self, bot_id: &Uuid, name: &str, data: &[u8]) -> BerylliumResult<()> {
+        let bytes = self.encrypt(bot_id, &format!("data/{}", name), data)?;
+        self.inner.save_data(bot_id, name, &bytes)
+    }
+
+    fn load_identity(&self, bot_id: &Uuid) -> BerylliumResult<Option<Vec<u8>>> {
+        let data = self.inner.load_identity(bot_id)?;
+        self.decrypt(bot_id, "identity", data)
+    }
+
+    fn save_identity(&self, bot_id: &Uuid, data: &[u8]) -> BerylliumResult<()> {
+        let bytes = self.encrypt(bot_id, "identity", data)?;
+        self.inner.save_identity(bot_id, &bytes)
+    }
+
+    fn load_prekey(&self, bot_id: &Uuid, id: u16) -> BerylliumResult<Option<Vec<u8>>> {
+        let data = self.inner.load_prekey(bot_id, id)?;
+        self.decrypt(bot_id, &format!("prekeys/{}", id), data)
+    }
+
+    fn save_prekey(&self, bot_id: &Uuid, id: u16, data: &[u8]) -> BerylliumResult<()> {
+        let bytes = self.encrypt(bot_id, &format!("prekeys/{}", id), data)?;
+        self.inner.save_prekey(bot_id, id, &bytes)
+    }
+
+    fn delete_prekey(&self, bot_id: &Uuid, id: u16) -> BerylliumResult<()> {
+        self.inner.delete_prekey(bot_id, id)
+    }
+
+    fn load_session(&self, bot_id: &Uuid, session_id: &str) -> BerylliumResult<Option<Vec<u8>>> {
+        let data = self.inner.load_session(bot_id, session_id)?;
+        self.decrypt(bot_id, &format!("sessions/{}", session_id), data)
+    }
+
+    fn save_session(&self, bot_id: &Uuid, session_id: &str, data: &[u8]) -> BerylliumResult<()> {
+        let bytes = self.encrypt(bot_id, &format!("sessions/{}", session_id), data)?;
+        self.inner.save_session(bot_id, session_id, &bytes)
+    }
+
+    // Legacy data was never encrypted.
+    fn load_legacy_identity(&self, bot_id: &Uuid) -> BerylliumResult<Option<Vec<u8>>> {
+        self.inner.load_legacy_identity(bot_id)
+    }
+
+    fn remove_legacy(&self, bot_id: &Uuid) -> BerylliumResult<()> {
+        self.inner.remove_legacy(bot_id)
+    }
+
+    fn list_bots(&self) -> BerylliumResult<Vec<Uuid>> {
+        self.inner.list_bots()
+    }
+
+    fn list_data(&self, bot_id: &Uuid) -> BerylliumResult<Vec<String>> {
+        self.inner.list_data(bot_id)
+    }
+
+    fn list_prekeys(&self, bot_id: &Uuid) -> BerylliumResult<Vec<u16>> {
+        self.inner.list_prekeys(bot_id)
+    }
+
+    fn list_sessions(&self, bot_id: &Uuid) -> BerylliumResult<Vec<String>> {
+        self.inner.list_sessions(bot_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use storage::{BotStore, FileStore, MemoryStore, bot_ids, temp_path};
+    use super::{EncryptedStore, HEADER_LEN, MAGIC, MasterKey, TAG_LEN};
+
+    fn store_with_state(key: &MasterKey) -> EncryptedStore<MemoryStore> {
+        let store = EncryptedStore::new(MemoryStore::new(), key.clone());
+        store.save_state(&bot_ids().0, b"hello").unwrap();
+        store
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let key = MasterKey::generate().unwrap();
+        let store = store_with_state(&key);
+        let (id, _) = bot_ids();
+        assert_eq!(store.load_state(&id).unwrap().unwrap(), b"hello");
+        assert!(store.load_devices(&id).unwrap().is_none());
+
+        let raw = store.inner.load_state(&id).unwrap().unwrap();
+        assert!(raw.starts_with(MAGIC));
+        assert_eq!(raw.len(), HEADER_LEN + 5 + TAG_LEN);
+        assert!(!raw.windows(5).any(|w| w == b"hello"));
+    }
+
+    #[test]
+    fn test_unknown_key_id() {
+        let store = store_with_state(&MasterKey::generate().unwrap());
+        let store = EncryptedStore::new(store.inner, MasterKey::generate().unwrap());
+        assert!(store.load_state(&bot_ids().0).is_err());
+    }
+
+    #[test]
+    fn test_wrong_key() {
+        let key = MasterKey::generate().unwrap();
+        let store = store_with_state(&key);
+        // Same ID, but different key.
+        let mut wrong = MasterKey::generate().unwrap();
+        wrong.id = key.id.clone();
+        let store = EncryptedStore::new(store.inner, wrong);
+        assert!(store.load_state(&bot_ids().0).is_err());
+    }
+
+    #[test]
+    fn test_tampered_data() {
+        let store = store_with_state(&MasterKey::generate().unwrap());
+        let (id, _) = bot_ids();
+        let raw = store.inner.load_state(&id).unwrap().unwrap();
+        // Flip a byte in the ciphertext, then in the tag.
+        for &i in &[HEADER_LEN, raw.len() - 1] {
+            let mut bytes = raw.clone();
+            bytes[i] ^= 1;
+            store.inner.save_state(&id, &bytes).unwrap();
+            assert!(store.load_state(&id).is_err());
+        }
+
+        store.inner.save_state(&id, &raw).unwrap();
+        assert_eq!(store.load_state(&id).unwrap().unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_truncated_data() {
+        let store = store_with_state(&MasterKey::generate().unwrap());
+        let (id, _) = bot_ids();
+        let raw = store.inner.load_state(&id).unwrap().unwrap();
+        for &len in &[MAGIC.len(), HEADER_LEN, HEADER_LEN + TAG_LEN - 1, raw.len() - 1] {
+            store.inner.save_state(&id, &raw[..len]).unwrap();
+            assert!(store.load_state(&id).is_err());
+        }
+
+        // Unsupported version
+        let mut bytes = raw.clone();
+        bytes[MAGIC.len()] += 1;
+        store.inner.save_state(&id, &bytes).unwrap();
+        assert!(store.load_state(&id).is_err());
+    }
+
+    #[test]
+    fn test_moved_data() {
+        let store = store_with_state(&MasterKey::generate().unwrap());
+        let (id, other_id) = bot_ids();
+        let raw = store.inner.load_state(&id).unwrap().unwrap();
+
+        // Another bot
+        store.inner.save_state(&other_id, &raw).unwrap();
+        assert!(store.load_state(&other_id).is_err());
+        // Another kind
+        store.inner.save_devices(&id, &raw).unwrap();
+        assert!(store.load_devices(&id).is_err());
+        store.inner.save_data(&id, "state", &raw).unwrap();
+        assert!(store.load_data(&id, "state").is_err());
+        // Another session
+        store.save_session(&id, "alice_a", b"session").unwrap();
+        let raw = store.inner.load_session(&id, "alice_a").unwrap().unwrap();
+        store.inner.save_session(&id, "bob_b", &raw).unwrap();
+        assert!(store.load_session(&id, "bob_b").is_err());
+    }
+
+    #[test]
+    fn test_plaintext() {
+        let (id, _) = bot_ids();
+        let inner = MemoryStore::new();
+        inner.save_state(&id, b"hello").unwrap();
+        let store = EncryptedStore::new(inner, MasterKey::generate().unwrap());
+        assert!(store.load_state(&id).is_err());
+
+        let store = store.allow_plaintext();
+        assert_eq!(store.load_state(&id).unwrap().unwrap(), b"hello");
+        assert_eq!(store.rotate().unwrap(), 1);
+        assert!(store.inner.load_state(&id).unwrap().unwrap().starts_with(MAGIC));
+    }
+
+    #[test]
+    fn test_rotate() {
+        let (id, _) = bot_ids();
+        let (old_key, new_key) = (MasterKey::generate().unwrap(), MasterKey::generate().unwrap());
+        let store = store_with_state(&old_key);
+        store.save_devices(&id, b"devices").unwrap();
+        store.save_identity(&id, b"identity").unwrap();
+        store.save_data(&id, "polls", b"data").unwrap();
+        store.save_prekey(&id, 7, b"prekey").unwrap();
+        store.save_session(&id, "alice_a", b"session").unwrap();
+
+        let store = EncryptedStore::new(store.inner, new_key.clone()).with_old_key(old_key);
+        assert_eq!(store.load_state(&id).unwrap().unwrap(), b"hello");
+        assert_eq!(store.rotate().unwrap(), 1);
+
+        // Everything should be readable (and encrypted) with only the new key.
+        let store = EncryptedStore::new(store.inner, new_key.clone());
+        assert_eq!(store.load_state(&id).unwrap().unwrap(), b"hello");
+        assert_eq!(store.load_devices(&id).unwrap().unwrap(), b"devices");
+        assert_eq!(store.load_identity(&id).unwrap().unwrap(), b"identity");
+        assert_eq!(store.load_data(&id, "polls").unwrap().unwrap(), b"data");
+        assert_eq!(store.load_prekey(&id, 7).unwrap().unwrap(), b"prekey");
+        assert_eq!(store.load_session(&id, "alice_a").unwrap().unwrap(), b"session");
+
+        let raw = store.inner.load_session(&id, "alice_a").unwrap().unwrap();
+        assert_eq!(&raw[MAGIC.len() + 1..MAGIC.len() + 1 + new_key.id.len()], &new_key.id[..]);
+    }
+
+    #[test]
+    fn test_rotate_removes_cryptobox_identity() {
+        let (id, _) = bot_ids();
+        let path = temp_path("rotate");
+        let bot_path = path.join(id.to_string());
+        fs::create_dir_all(bot_path.join("identities")).unwrap();
+        File::create(bot_path.join("identities").join("local")).unwrap();
+
+        let inner = FileStore::new(&path);
+        inner.save_identity(&id, b"identity").unwrap();
+        let key = MasterKey::generate().unwrap();
+        let store = EncryptedStore::new(inner, key).allow_plaintext();
+        assert_eq!(store.rotate().unwrap(), 1);
+
+        assert!(!bot_path.join("identities").exists());
+        assert_eq!(store.load_identity(&id).unwrap().unwrap(), b"identity");
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
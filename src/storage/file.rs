@@ -0,0 +1,208 @@
+use cryptobox::CBox;
+use errors::{BerylliumError, BerylliumResult};
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use super::BotStore;
+use uuid::Uuid;
+
+/// Write the data to a temporary file and then rename it to the given path,
+/// so that we never end up with a partially written file (if we crash midway).
+fn write_file(path: &Path, data: &[u8]) -> BerylliumResult<()> {
+    if let Some(dir) = path.parent() {
+        if !dir.is_dir() {
+            info!("Creating {}", dir.display());
+            fs::create_dir_all(dir)?;
+        }
+    }
+
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    {
+        let mut fd = File::create(&temp_path)?;
+        fd.write_all(data)?;
+        fd.sync_all()?;
+    }
+
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+/// Read the file (if it exists).
+fn read_file(path: &Path) -> BerylliumResult<Option<Vec<u8>>> {
+    let mut fd = match File::open(path) {
+        Ok(fd) => fd,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut bytes = vec![];
+    fd.read_to_end(&mut bytes)?;
+    Ok(Some(bytes))
+}
+
+/// Names of the files in the given directory (ignoring the temporary files).
+fn list_files(path: &Path) -> BerylliumResult<Vec<String>> {
+    let mut names = vec![];
+    if !path.is_dir() {
+        return Ok(names)
+    }
+
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if !entry.path().is_file() {
+            continue
+        }
+
+        match entry.file_name().into_string() {
+            Ok(ref name) if name.ends_with(".tmp") => (),
+            Ok(name) => names.push(name),
+            Err(name) => info!("Ignoring {:?}", name),
+        }
+    }
+
+    Ok(names)
+}
+
+/// Stores the data of each bot in its own directory (named by the bot ID)
+/// inside the given path. Sessions and prekeys use the same layout as cryptobox.
+pub struct FileStore {
+    path: PathBuf,
+}
+
+impl FileStore {
+    pub fn new<P>(path: P) -> FileStore
+        where P: AsRef<Path>
+    {
+        FileStore {
+            path: PathBuf::from(path.as_ref()),
+        }
+    }
+
+    fn bot_path(&self, bot_id: &Uuid) -> PathBuf {
+        self.path.join(bot_id.to_string())
+    }
+}
+
+impl BotStore for FileStore {
+    fn load_state(&self, bot_id: &Uuid) -> BerylliumResult<Option<Vec<u8>>> {
+        read_file(&self.bot_path(bot_id).join("bot_data.json"))
+    }
+
+    fn save_state(&self, bot_id: &Uuid, data: &[u8]) -> BerylliumResult<()> {
+        write_file(&self.bot_path(bot_id).join("bot_data.json"), data)
+    }
+
+    fn load_devices(&self, bot_id: &Uuid) -> BerylliumResult<Option<Vec<u8>>> {
+        read_file(&self.bot_path(bot_id).join("devices.json"))
+    }
+
+    fn save_devices(&self, bot_id: &Uuid, data: &[u8]) -> BerylliumResult<()> {
+        write_file(&self.bot_path(bot_id).join("devices.json"), data)
+    }
+
+    fn load_data(&self, bot_id: &Uuid, name: &str) -> BerylliumResult<Option<Vec<u8>>> {
+        read_file(&self.bot_path(bot_id).join("data").join(format!("{}.json", name)))
+    }
+
+    fn save_data(&self, bot_id: &Uuid, name: &str, data: &[u8]) -> BerylliumResult<()> {
+        write_file(&self.bot_path(bot_id).join("data").join(format!("{}.json", name)), data)
+    }
+
+    fn load_identity(&self, bot_id: &Uuid) -> BerylliumResult<Option<Vec<u8>>> {
+        read_file(&self.bot_path(bot_id).join("identity"))
+    }
+
+    fn save_identity(&self, bot_id: &Uuid, data: &[u8]) -> BerylliumResult<()> {
+        write_file(&self.bot_path(bot_id).join("identity"), data)
+    }
+
+    fn load_prekey(&self, bot_id: &Uuid, id: u16) -> BerylliumResult<Option<Vec<u8>>> {
+        read_file(&self.bot_path(bot_id).join("prekeys").join(id.to_string()))
+    }
+
+    fn save_prekey(&self, bot_id: &Uuid, id: u16, data: &[u8]) -> BerylliumResult<()> {
+        write_file(&self.bot_path(bot_id).join("prekeys").join(id.to_string()), data)
+    }
+
+    fn delete_prekey(&self, bot_id: &Uuid, id: u16) -> BerylliumResult<()> {
+        match fs::remove_file(self.bot_path(bot_id).join("prekeys").join(id.to_string())) {
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            res => Ok(res?),
+        }
+    }
+
+    fn load_session(&self, bot_id: &Uuid, session_id: &str) -> BerylliumResult<Option<Vec<u8>>> {
+        read_file(&self.bot_path(bot_id).join("sessions").join(session_id))
+    }
+
+    fn save_session(&self, bot_id: &Uuid, session_id: &str, data: &[u8]) -> BerylliumResult<()> {
+        write_file(&self.bot_path(bot_id).join("sessions").join(session_id), data)
+    }
+
+    fn load_legacy_identity(&self, bot_id: &Uuid) -> BerylliumResult<Option<Vec<u8>>> {
+        // Bots created before we had our own storage have their identity
+        // in the cryptobox format, so we get it through cryptobox.
+        let path = self.bot_path(bot_id);
+        if !path.join("identities").join("local").is_file() {
+            return Ok(None)
+        }
+
+        let cbox = CBox::file_open(&path).map_err(|e| {
+            BerylliumError::Session(format!("Cannot open cryptobox: {}", e))
+        })?;
+        Ok(Some(cbox.identity().serialise()?))
+    }
+
+    fn remove_legacy(&self, bot_id: &Uuid) -> BerylliumResult<()> {
+        // Prekeys and sessions have the same layout as cryptobox (so, they're
+        // overwritten when they're saved again), but the identity has moved.
+        let path = self.bot_path(bot_id);
+        if !path.join("identity").is_file() {
+            return Ok(())
+        }
+
+        match fs::remove_dir_all(path.join("identities")) {
+            Ok(()) => info!("Removed cryptobox identity of bot {}", bot_id),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok(())
+    }
+
+    fn list_bots(&self) -> BerylliumResult<Vec<Uuid>> {
+        let mut bots = vec![];
+        if !self.path.is_dir() {
+            return Ok(bots)
+        }
+
+        for entry in fs::read_dir(&self.path)? {
+            let entry = entry?;
+            if !entry.path().is_dir() {
+                continue
+            }
+
+            if let Some(id) = entry.file_name().to_str().and_then(|n| n.parse().ok()) {
+                bots.push(id);
+            }
+        }
+
+        Ok(bots)
+    }
+
+    fn list_data(&self, bot_id: &Uuid) -> BerylliumResult<Vec<String>> {
+        let names = list_files(&self.bot_path(bot_id).join("data"))?;
+        Ok(names.into_iter().filter(|n| n.ends_with(".json"))
+                .map(|n| n[..n.len() - 5].to_owned()).collect())
+    }
+
+    fn list_prekeys(&self, bot_id: &Uuid) -> BerylliumResult<Vec<u16>> {
+        let names = list_files(&self.bot_path(bot_id).join("prekeys"))?;
+        Ok(names.iter().filter_map(|n| n.parse().ok()).collect())
+    }
+
+    fn list_sessions(&self, bot_id: &Uuid) -> BerylliumResult<Vec<String>> {
+        list_files(&self.bot_path(bot_id).join("sessions"))
+    }
+}
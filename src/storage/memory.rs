@@ -0,0 +1,112 @@
+use errors::BerylliumResult;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use super::BotStore;
+use uuid::Uuid;
+
+/// Keeps everything in memory (which is lost when the service stops).
+/// This is useful for tests, or for bots which don't need to survive restarts.
+#[derive(Default)]
+pub struct MemoryStore {
+    // Bot ID -> (key -> value)
+    bots: Mutex<HashMap<Uuid, HashMap<String, Vec<u8>>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    fn get(&self, bot_id: &Uuid, key: &str) -> BerylliumResult<Option<Vec<u8>>> {
+        Ok(self.bots.lock().get(bot_id).and_then(|map| map.get(key)).cloned())
+    }
+
+    /// Keys (without the prefix) with the given prefix.
+    fn keys(&self, bot_id: &Uuid, prefix: &str) -> Vec<String> {
+        match self.bots.lock().get(bot_id) {
+            Some(map) => map.keys().filter(|k| k.starts_with(prefix))
+                            .map(|k| k[prefix.len()..].to_owned()).collect(),
+            None => vec![],
+        }
+    }
+
+    fn set(&self, bot_id: &Uuid, key: String, data: &[u8]) -> BerylliumResult<()> {
+        let mut bots = self.bots.lock();
+        bots.entry(*bot_id).or_insert_with(HashMap::new).insert(key, data.to_owned());
+        Ok(())
+    }
+}
+
+impl BotStore for MemoryStore {
+    fn load_state(&self, bot_id: &Uuid) -> BerylliumResult<Option<Vec<u8>>> {
+        self.get(bot_id, "state")
+    }
+
+    fn save_state(&self, bot_id: &Uuid, data: &[u8]) -> BerylliumResult<()> {
+        self.set(bot_id, String::from("state"), data)
+    }
+
+    fn load_devices(&self, bot_id: &Uuid) -> BerylliumResult<Option<Vec<u8>>> {
+        self.get(bot_id, "devices")
+    }
+
+    fn save_devices(&self, bot_id: &Uuid, data: &[u8]) -> BerylliumResult<()> {
+        self.set(bot_id, String::from("devices"), data)
+    }
+
+    fn load_data(&self, bot_id: &Uuid, name: &str) -> BerylliumResult<Option<Vec<u8>>> {
+        self.get(bot_id, &format!("data/{}", name))
+    }
+
+    fn save_data(&self, bot_id: &Uuid, name: &str, data: &[u8]) -> BerylliumResult<()> {
+        self.set(bot_id, format!("data/{}", name), data)
+    }
+
+    fn load_identity(&self, bot_id: &Uuid) -> BerylliumResult<Option<Vec<u8>>> {
+        self.get(bot_id, "identity")
+    }
+
+    fn save_identity(&self, bot_id: &Uuid, data: &[u8]) -> BerylliumResult<()> {
+        self.set(bot_id, String::from("identity"), data)
+    }
+
+    fn load_prekey(&self, bot_id: &Uuid, id: u16) -> BerylliumResult<Option<Vec<u8>>> {
+        self.get(bot_id, &format!("prekeys/{}", id))
+    }
+
+    fn save_prekey(&self, bot_id: &Uuid, id: u16, data: &[u8]) -> BerylliumResult<()> {
+        self.set(bot_id, format!("prekeys/{}", id), data)
+    }
+
+    fn delete_prekey(&self, bot_id: &Uuid, id: u16) -> BerylliumResult<()> {
+        if let Some(map) = self.bots.lock().get_mut(bot_id) {
+            map.remove(&format!("prekeys/{}", id));
+        }
+
+        Ok(())
+    }
+
+    fn load_session(&self, bot_id: &Uuid, session_id: &str) -> BerylliumResult<Option<Vec<u8>>> {
+        self.get(bot_id, &format!("sessions/{}", session_id))
+    }
+
+    fn save_session(&self, bot_id: &Uuid, session_id: &str, data: &[u8]) -> BerylliumResult<()> {
+        self.set(bot_id, format!("sessions/{}", session_id), data)
+    }
+
+    fn list_bots(&self) -> BerylliumResult<Vec<Uuid>> {
+        Ok(self.bots.lock().keys().cloned().collect())
+    }
+
+    fn list_data(&self, bot_id: &Uuid) -> BerylliumResult<Vec<String>> {
+        Ok(self.keys(bot_id, "data/"))
+    }
+
+    fn list_prekeys(&self, bot_id: &Uuid) -> BerylliumResult<Vec<u16>> {
+        Ok(self.keys(bot_id, "prekeys/").iter().filter_map(|k| k.parse().ok()).collect())
+    }
+
+    fn list_sessions(&self, bot_id: &Uuid) -> BerylliumResult<Vec<String>> {
+        Ok(self.keys(bot_id, "sessions/"))
+    }
+}
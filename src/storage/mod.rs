@@ -0,0 +1,508 @@
+//! Storage of bot instances.
+//!
+//! Everything a bot needs to persist (its state, the known devices, its data,
+//! and the cryptographic identity, prekeys and sessions) goes through a `BotStore`.
+//! By default, the data is stored in files (`FileStore`), but any implementation
+//! of the trait can be used (for example, `MemoryStore` for tests, or `SqliteStore`
+//! with the `sqlite` feature). Any store can be wrapped in an `EncryptedStore`
+//! to encrypt the data at rest.
+
+mod encrypted;
+mod file;
+mod memory;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+pub use self::encrypted::{EncryptedStore, MasterKey};
+pub use self::file::FileStore;
+pub use self::memory::MemoryStore;
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::SqliteStore;
+
+use base64;
+use errors::{BerylliumError, BerylliumResult};
+use proteus::keys::{IdentityKeyPair, PreKey, PreKeyBundle, PreKeyId};
+use proteus::message::Envelope;
+use proteus::session::{DecryptError, PreKeyStore, Session};
+use serde::{Deserialize, Serialize};
+use serde_json;
+use std::{iter, u16};
+use std::collections::HashMap;
+use std::sync::Arc;
+use types::EncodedPreKey;
+use uuid::Uuid;
+
+/// Storage backend for the data of all bot instances. All data is handed over
+/// as (already serialized) bytes, and it's identified by the bot ID.
+///
+/// Implementations are shared between threads, so they should take care of
+/// their own synchronization.
+pub trait BotStore: Send + Sync + 'static {
+    /// Load the state (i.e., creation data) of a bot (if it exists).
+    fn load_state(&self, bot_id: &Uuid) -> BerylliumResult<Option<Vec<u8>>>;
+
+    /// Save the state of a bot (replacing the old one, if any).
+    fn save_state(&self, bot_id: &Uuid, data: &[u8]) -> BerylliumResult<()>;
+
+    /// Load the devices known to a bot.
+    fn load_devices(&self, bot_id: &Uuid) -> BerylliumResult<Option<Vec<u8>>>;
+
+    /// Save the devices known to a bot.
+    fn save_devices(&self, bot_id: &Uuid, data: &[u8]) -> BerylliumResult<()>;
+
+    /// Load the data saved by a bot with the given name.
+    fn load_data(&self, bot_id: &Uuid, name: &str) -> BerylliumResult<Option<Vec<u8>>>;
+
+    /// Save some data of a bot with the given name.
+    fn save_data(&self, bot_id: &Uuid, name: &str, data: &[u8]) -> BerylliumResult<()>;
+
+    /// Load the (Proteus) identity keypair of a bot.
+    fn load_identity(&self, bot_id: &Uuid) -> BerylliumResult<Option<Vec<u8>>>;
+
+    /// Save the identity keypair of a bot.
+    fn save_identity(&self, bot_id: &Uuid, data: &[u8]) -> BerylliumResult<()>;
+
+    /// Load a prekey of a bot.
+    fn load_prekey(&self, bot_id: &Uuid, id: u16) -> BerylliumResult<Option<Vec<u8>>>;
+
+    /// Save a prekey of a bot.
+    fn save_prekey(&self, bot_id: &Uuid, id: u16, data: &[u8]) -> BerylliumResult<()>;
+
+    /// Delete a prekey of a bot (once it's been used for initiating a session).
+    fn delete_prekey(&self, bot_id: &Uuid, id: u16) -> BerylliumResult<()>;
+
+    /// Load the session of a bot with some user's client. The session ID is of
+    /// the form `{user_id}_{client_id}`.
+    fn load_session(&self, bot_id: &Uuid, session_id: &str) -> BerylliumResult<Option<Vec<u8>>>;
+
+    /// Save the session of a bot with some user's client.
+    fn save_session(&self, bot_id: &Uuid, session_id: &str, data: &[u8]) -> BerylliumResult<()>;
+
+    /// Load the identity keypair saved by older versions of the store (if any).
+    /// This should never change the store - the identity is migrated by saving
+    /// it through `save_identity` and then calling `remove_legacy`.
+    fn load_legacy_identity(&self, _bot_id: &Uuid) -> BerylliumResult<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    /// Remove the data left behind by older versions of the store, once the bot's
+    /// data has been saved again (for example, after re-encrypting it).
+    fn remove_legacy(&self, _bot_id: &Uuid) -> BerylliumResult<()> {
+        Ok(())
+    }
+
+    // The listing methods are only needed for working on whole stores (like
+    // `copy_bots`), so stores which can't list their contents needn't implement them.
+
+    /// IDs of all the bots in this store.
+    fn list_bots(&self) -> BerylliumResult<Vec<Uuid>> {
+        Err(unsupported("Listing bots"))
+    }
+
+    /// Names of all the data saved by a bot.
+    fn list_data(&self, _bot_id: &Uuid) -> BerylliumResult<Vec<String>> {
+        Err(unsupported("Listing data"))
+    }
+
+    /// IDs of all the prekeys of a bot.
+    fn list_prekeys(&self, _bot_id: &Uuid) -> BerylliumResult<Vec<u16>> {
+        Err(unsupported("Listing prekeys"))
+    }
+
+    /// IDs of all the sessions of a bot.
+    fn list_sessions(&self, _bot_id: &Uuid) -> BerylliumResult<Vec<String>> {
+        Err(unsupported("Listing sessions"))
+    }
+}
+
+fn unsupported(what: &str) -> BerylliumError {
+    BerylliumError::Other(format!("{} is not supported by this store", what))
+}
+
+/// Load the identity of a bot, or its legacy identity if it hasn't been migrated yet.
+fn load_any_identity(store: &BotStore, bot_id: &Uuid) -> BerylliumResult<Option<Vec<u8>>> {
+    match store.load_identity(bot_id)? {
+        Some(bytes) => Ok(Some(bytes)),
+        None => store.load_legacy_identity(bot_id),
+    }
+}
+
+/// Copy everything of all the bots in one store to another (for example, when
+/// moving from files to a database). Returns the number of bots copied.
+pub fn copy_bots(source: &BotStore, target: &BotStore) -> BerylliumResult<usize> {
+    let bots = source.list_bots()?;
+    for bot_id in &bots {
+        info!("Copying bot {}", bot_id);
+        if let Some(bytes) = source.load_state(bot_id)? {
+            target.save_state(bot_id, &bytes)?;
+        }
+
+        if let Some(bytes) = source.load_devices(bot_id)? {
+            target.save_devices(bot_id, &bytes)?;
+        }
+
+        if let Some(bytes) = load_any_identity(source, bot_id)? {
+            target.save_identity(bot_id, &bytes)?;
+        }
+
+        for name in source.list_data(bot_id)? {
+            if let Some(bytes) = source.load_data(bot_id, &name)? {
+                target.save_data(bot_id, &name, &bytes)?;
+            }
+        }
+
+        for id in source.list_prekeys(bot_id)? {
+            if let Some(bytes) = source.load_prekey(bot_id, id)? {
+                target.save_prekey(bot_id, id, &bytes)?;
+            }
+        }
+
+        for id in source.list_sessions(bot_id)? {
+            if let Some(bytes) = source.load_session(bot_id, &id)? {
+                target.save_session(bot_id, &id, &bytes)?;
+            }
+        }
+    }
+
+    Ok(bots.len())
+}
+
+/// Prekeys of a bot, used by Proteus when a session is initiated by a message.
+struct PreKeys<'a> {
+    store: &'a BotStore,
+    bot_id: &'a Uuid,
+    /// Prekeys used by the new sessions. These are removed only after
+    /// the sessions have been saved.
+    used: Vec<u16>,
+}
+
+impl<'a> PreKeys<'a> {
+    fn new(store: &'a BotStore, bot_id: &'a Uuid) -> PreKeys<'a> {
+        PreKeys { store, bot_id, used: vec![] }
+    }
+
+    fn remove_used(self) -> BerylliumResult<()> {
+        for id in self.used {
+            // The last resort prekey is never removed.
+            if id != u16::MAX {
+                self.store.delete_prekey(self.bot_id, id)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> PreKeyStore for PreKeys<'a> {
+    type Error = BerylliumError;
+
+    fn prekey(&mut self, id: PreKeyId) -> Result<Option<PreKey>, BerylliumError> {
+        match self.store.load_prekey(self.bot_id, id.value())? {
+            Some(bytes) => Ok(Some(PreKey::deserialise(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn remove(&mut self, id: PreKeyId) -> Result<(), BerylliumError> {
+        self.used.push(id.value());
+        Ok(())
+    }
+}
+
+fn decrypt_error(e: DecryptError<BerylliumError>) -> BerylliumError {
+    match e {
+        DecryptError::PreKeyStoreError(e) => e,
+        e => BerylliumError::Session(format!("{:?}", e)),
+    }
+}
+
+pub struct StorageManager {
+    id: Uuid,
+    store: Arc<BotStore>,
+    identity: IdentityKeyPair,
+}
+
+impl StorageManager {
+    /// Load (or create) the identity of the given bot from the store.
+    pub fn new(id: Uuid, store: Arc<BotStore>) -> BerylliumResult<Self> {
+        let identity = match store.load_identity(&id)? {
+            Some(bytes) => IdentityKeyPair::deserialise(&bytes)?,
+            None => match store.load_legacy_identity(&id)? {
+                // Save it in the new format (say, encrypted) before removing the old one.
+                Some(bytes) => {
+                    info!("Migrating legacy identity of bot {}", id);
+                    let identity = IdentityKeyPair::deserialise(&bytes)?;
+                    store.save_identity(&id, &bytes)?;
+                    store.remove_legacy(&id)?;
+                    identity
+                },
+                None => {
+                    info!("Creating new identity for bot {}", id);
+                    let identity = IdentityKeyPair::new();
+                    store.save_identity(&id, &identity.serialise()?)?;
+                    identity
+                },
+            },
+        };
+
+        Ok(StorageManager {
+            id: id,
+            store: store,
+            identity: identity,
+        })
+    }
+
+    /// ID of the bot whose data is managed.
+    pub fn id(&self) -> &Uuid {
+        &self.id
+    }
+
+    pub fn initialize_prekeys(&self, keys: usize) -> BerylliumResult<Vec<EncodedPreKey>> {
+        let mut vec = Vec::with_capacity(8 * keys + 1);
+        for i in (0..8 * (keys as u16)).chain(iter::once(u16::MAX)) {
+            let key = PreKey::new(PreKeyId::new(i));
+            self.store.save_prekey(&self.id, i, &key.serialise()?)?;
+            let bundle = PreKeyBundle::new(self.identity.public_key.clone(), &key);
+            let encoded = EncodedPreKey {
+                id: i,
+                key: base64::encode(&bundle.serialise()?)
+            };
+
+            vec.push(encoded);
+        }
+
+        Ok(vec)
+    }
+
+    pub fn save_state<T>(&self, data: &T) -> BerylliumResult<()>
+        where T: Serialize
+    {
+        self.store.save_state(&self.id, &serde_json::to_vec(data)?)
+    }
+
+    pub fn load_state<T>(&self) -> BerylliumResult<T>
+        where for<'de> T: Deserialize<'de>
+    {
+        match self.store.load_state(&self.id)? {
+            Some(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            None => Err(BerylliumError::Other(format!("Missing state for bot {}", self.id))),
+        }
+    }
+
+    /// Save the known devices (user ID -> client IDs) of the conversation.
+    pub fn save_devices(&self, devices: &HashMap<String, Vec<String>>) -> BerylliumResult<()> {
+        self.store.save_devices(&self.id, &serde_json::to_vec(devices)?)
+    }
+
+    /// Load the known devices (if we've saved them before).
+    pub fn load_devices(&self) -> BerylliumResult<HashMap<String, Vec<String>>> {
+        match self.store.load_devices(&self.id)? {
+            Some(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            None => Ok(HashMap::new()),
+        }
+    }
+
+    /// Store some (JSON-serializable) data for this bot under the given name.
+    pub fn save_data<T>(&self, name: &str, data: &T) -> BerylliumResult<()>
+        where T: Serialize
+    {
+        self.store.save_data(&self.id, name, &serde_json::to_vec(data)?)
+    }
+
+    /// Load the data stored under the given name (if any).
+    pub fn load_data<T>(&self, name: &str) -> BerylliumResult<Option<T>>
+        where for<'de> T: Deserialize<'de>
+    {
+        match self.store.load_data(&self.id, name)? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn load_session(&self, id: &str) -> BerylliumResult<Option<Session<&IdentityKeyPair>>> {
+        match self.store.load_session(&self.id, id)? {
+            Some(bytes) => Ok(Some(Session::deserialise(&self.identity, &bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn save_session(&self, id: &str, session: &Session<&IdentityKeyPair>) -> BerylliumResult<()> {
+        self.store.save_session(&self.id, id, &session.serialise()?)
+    }
+
+    pub fn encrypt(&self, user_id: &str, client_id: &str,
+                   data: &[u8], prekey: &[u8]) -> BerylliumResult<String>
+    {
+        let id = format!("{}_{}", user_id, client_id);
+        let mut session = match self.load_session(&id)? {
+            Some(sess) => sess,
+            None => {
+                info!("Couldn't find session for id: {}", id);
+                let bundle = PreKeyBundle::deserialise(prekey)?;
+                Session::init_from_prekey(&self.identity, bundle)
+            },
+        };
+
+        let data = session.encrypt(data)?.serialise()?;
+        self.save_session(&id, &session)?;
+        Ok(base64::encode(&data))
+    }
+
+    pub fn encrypt_for_devices<'a>(&self, data: &[u8],
+                                   devices: &'a HashMap<String, Vec<String>>)
+                                  -> HashMap<&'a str, HashMap<&'a str, String>>
+    {
+        let mut map = HashMap::with_capacity(devices.len());
+        for (key, clients) in devices {
+            for client in clients {
+                let id = format!("{}_{}", key, client);
+                match self.load_session(&id) {
+                    Ok(Some(mut session)) => {
+                        let cypher = session.encrypt(data).and_then(|env| env.serialise()).ok();
+                        if self.save_session(&id, &session).is_err() {
+                            // Should we ignore if we can't save the session?
+                            continue
+                        }
+
+                        if let Some(c) = cypher {
+                            let clients = map.entry(key.as_str())
+                                             .or_insert(HashMap::new());
+                            let encoded = base64::encode(&c);
+                            clients.entry(client.as_str()).or_insert(encoded);
+                        }
+                    },
+                    _ => continue,
+                }
+            }
+        }
+
+        map
+    }
+
+    pub fn decrypt(&self, user_id: &str, client_id: &str,
+                   data: &str) -> BerylliumResult<Vec<u8>>
+    {
+        let id = format!("{}_{}", user_id, client_id);
+        let bytes = base64::decode(&data)?;
+        let envelope = Envelope::deserialise(&bytes)?;
+        let mut prekeys = PreKeys::new(&*self.store, &self.id);
+        let plain_data = match self.load_session(&id)? {
+            Some(mut session) => {
+                let data = session.decrypt(&mut prekeys, &envelope).map_err(decrypt_error)?;
+                self.save_session(&id, &session)?;
+                data
+            },
+            None => {
+                info!("Couldn't find session for id: {}", id);
+                let (session, data) =
+                    Session::init_from_message(&self.identity, &mut prekeys, &envelope)
+                            .map_err(decrypt_error)?;
+                self.save_session(&id, &session)?;
+                data
+            },
+        };
+
+        prekeys.remove_used()?;
+        Ok(plain_data)
+    }
+}
+
+/// IDs of two bots (for tests).
+#[cfg(test)]
+pub(crate) fn bot_ids() -> (Uuid, Uuid) {
+    (Uuid::parse_str("7b8d3a36-5c5e-11e8-9c2d-fa7ae01bbebc").unwrap(),
+     Uuid::parse_str("7b8d3d88-5c5e-11e8-9c2d-fa7ae01bbebc").unwrap())
+}
+
+/// New (empty) path in the temporary directory (for tests).
+#[cfg(test)]
+pub(crate) fn temp_path(prefix: &str) -> ::std::path::PathBuf {
+    ::std::env::temp_dir().join(format!("beryllium-{}-{}", prefix, ::utils::uuid_v1()))
+}
+
+#[cfg(test)]
+mod tests {
+    use base64;
+    use cryptobox::CBox;
+    use proteus;
+    use std::fs;
+    use std::sync::Arc;
+    use super::{BotStore, EncryptedStore, FileStore, MasterKey, MemoryStore, StorageManager};
+    use super::{bot_ids, copy_bots, temp_path};
+
+    #[test]
+    fn test_identity_and_state() {
+        proteus::init();
+        let store: Arc<BotStore> = Arc::new(MemoryStore::new());
+        let (id, _) = bot_ids();
+        let storage = StorageManager::new(id, store.clone()).unwrap();
+        assert!(storage.load_state::<Vec<String>>().is_err());
+        storage.save_state(&vec!["foo", "bar"]).unwrap();
+
+        // Loading again should reuse the saved identity.
+        let other = StorageManager::new(id, store.clone()).unwrap();
+        assert_eq!(other.identity.serialise().unwrap(),
+                   storage.identity.serialise().unwrap());
+        assert_eq!(other.load_state::<Vec<String>>().unwrap(), vec!["foo", "bar"]);
+    }
+
+    #[test]
+    fn test_prekeys_and_sessions() {
+        proteus::init();
+        let store: Arc<BotStore> = Arc::new(MemoryStore::new());
+        let (alice_id, bob_id) = bot_ids();
+        let alice = StorageManager::new(alice_id, store.clone()).unwrap();
+        let bob = StorageManager::new(bob_id, store.clone()).unwrap();
+
+        let prekeys = alice.initialize_prekeys(1).unwrap();
+        assert_eq!(prekeys.len(), 9);
+        assert_eq!(prekeys.last().unwrap().id, u16::max_value());
+        assert!(store.load_prekey(&alice_id, 0).unwrap().is_some());
+
+        let bundle = base64::decode(&prekeys[0].key).unwrap();
+        let cipher = bob.encrypt("alice", "a", b"hello", &bundle).unwrap();
+        assert_eq!(alice.decrypt("bob", "b", &cipher).unwrap(), b"hello");
+        // Used prekeys are removed (except the last resort key).
+        assert!(store.load_prekey(&alice_id, 0).unwrap().is_none());
+        assert!(store.load_session(&alice_id, "bob_b").unwrap().is_some());
+        assert!(store.load_session(&bob_id, "alice_a").unwrap().is_some());
+
+        // Sessions survive reloading the managers.
+        let alice = StorageManager::new(alice_id, store.clone()).unwrap();
+        let bob = StorageManager::new(bob_id, store.clone()).unwrap();
+        let cipher = alice.encrypt("bob", "b", b"hi there", &[]).unwrap();
+        assert_eq!(bob.decrypt("alice", "a", &cipher).unwrap(), b"hi there");
+        let cipher = bob.encrypt("alice", "a", b"bye", &[]).unwrap();
+        assert_eq!(alice.decrypt("bob", "b", &cipher).unwrap(), b"bye");
+    }
+
+    #[test]
+    fn test_legacy_identity() {
+        proteus::init();
+        let (id, _) = bot_ids();
+        let path = temp_path("legacy");
+        let bot_path = path.join(id.to_string());
+        let legacy = CBox::file_open(&bot_path).unwrap().identity().serialise().unwrap();
+
+        // Reading (say, for copying to another store) shouldn't change the source.
+        let copy = MemoryStore::new();
+        assert_eq!(copy_bots(&FileStore::new(&path), &copy).unwrap(), 1);
+        assert_eq!(copy.load_identity(&id).unwrap().unwrap(), legacy);
+        assert!(bot_path.join("identities").join("local").is_file());
+        assert!(!bot_path.join("identity").exists());
+
+        // The identity is encrypted before removing the legacy one.
+        let key = MasterKey::generate().unwrap();
+        let store = EncryptedStore::new(FileStore::new(&path), key.clone());
+        let storage = StorageManager::new(id, Arc::new(store)).unwrap();
+        assert_eq!(storage.identity.serialise().unwrap(), legacy);
+        assert!(!bot_path.join("identities").exists());
+        let raw = FileStore::new(&path).load_identity(&id).unwrap().unwrap();
+        assert!(raw.starts_with(b"BEnc"));
+
+        let store = EncryptedStore::new(FileStore::new(&path), key);
+        let storage = StorageManager::new(id, Arc::new(store)).unwrap();
+        assert_eq!(storage.identity.serialise().unwrap(), legacy);
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,456 @@
+use errors::{BerylliumError, BerylliumResult};
+use parking_lot::Mutex;
+use rusqlite::{Connection, Error as SqliteError, OptionalExtension, Row, ToSql, Transaction};
+use rusqlite::types::{FromSql, Type};
+use serde::de::DeserializeOwned;
+use serde_json::{self, Value as SerdeValue};
+use std::{cmp, i64, u16};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use super::BotStore;
+use types::{BotCreationData, Conversation, Member, Origin};
+use uuid::Uuid;
+
+// The bot state (creation data) and the known devices are kept in their own
+// tables, so that they can be queried. Everything else is opaque to us (proteus
+// data, or the bot's own data), and is kept as a BLOB.
+const SCHEMA: &'static str = "
+    PRAGMA journal_mode = WAL;
+    CREATE TABLE IF NOT EXISTS bots (
+        id TEXT PRIMARY KEY NOT NULL,
+        client TEXT NOT NULL,
+        token TEXT NOT NULL,
+        locale TEXT NOT NULL,
+        ephemeral_timeout INTEGER,
+        origin_id TEXT NOT NULL,
+        origin_name TEXT NOT NULL,
+        origin_handle TEXT NOT NULL,
+        origin_accent_id INTEGER NOT NULL,
+        conversation_id TEXT NOT NULL,
+        conversation_name TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS members (
+        bot_id TEXT NOT NULL,
+        user_id TEXT NOT NULL,
+        status INTEGER NOT NULL,
+        PRIMARY KEY (bot_id, user_id)
+    );
+    CREATE TABLE IF NOT EXISTS devices (
+        bot_id TEXT NOT NULL,
+        user_id TEXT NOT NULL,
+        client_id TEXT NOT NULL,
+        PRIMARY KEY (bot_id, user_id, client_id)
+    );
+    CREATE TABLE IF NOT EXISTS sealed (
+        bot_id TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        data BLOB NOT NULL,
+        PRIMARY KEY (bot_id, kind)
+    );
+    CREATE TABLE IF NOT EXISTS bot_data (
+        bot_id TEXT NOT NULL,
+        name TEXT NOT NULL,
+        data BLOB NOT NULL,
+        PRIMARY KEY (bot_id, name)
+    );
+    CREATE TABLE IF NOT EXISTS identities (
+        bot_id TEXT PRIMARY KEY NOT NULL,
+        identity BLOB NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS prekeys (
+        bot_id TEXT NOT NULL,
+        id INTEGER NOT NULL,
+        prekey BLOB NOT NULL,
+        PRIMARY KEY (bot_id, id)
+    );
+    CREATE TABLE IF NOT EXISTS sessions (
+        bot_id TEXT NOT NULL,
+        session_id TEXT NOT NULL,
+        session BLOB NOT NULL,
+        PRIMARY KEY (bot_id, session_id)
+    );
+";
+
+/// Parse the JSON blob. Returns `None` if it's not JSON at all (for example,
+/// when it's been encrypted by an `EncryptedStore`), and an error if it's JSON
+/// which doesn't match the type.
+fn parse_json<T: DeserializeOwned>(data: &[u8]) -> BerylliumResult<Option<T>> {
+    match serde_json::from_slice::<SerdeValue>(data) {
+        Ok(value) => Ok(Some(serde_json::from_value(value)?)),
+        Err(_) => Ok(None),
+    }
+}
+
+fn get_uuid(row: &Row, idx: usize) -> Result<Uuid, SqliteError> {
+    let id: String = row.get(idx)?;
+    id.parse().map_err(|e| SqliteError::FromSqlConversionFailure(idx, Type::Text, Box::new(e)))
+}
+
+/// Stores the data of all bots in a single SQLite database. Every change
+/// happens in a single transaction, and hence, it's atomic.
+///
+/// The bot state and devices are split into the `bots`, `members` and `devices`
+/// tables. If they aren't JSON (for example, when this is wrapped by an
+/// `EncryptedStore`), then they're kept as-is in the `sealed` table.
+pub struct SqliteStore {
+    // `Connection` can be sent across threads, but it can't be shared.
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Open (or create) the database at the given path.
+    pub fn open<P>(path: P) -> BerylliumResult<SqliteStore>
+        where P: AsRef<Path>
+    {
+        info!("Opening SQLite store at {}", path.as_ref().display());
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(SqliteStore {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn get(&self, sql: &str, params: &[&ToSql]) -> BerylliumResult<Option<Vec<u8>>> {
+        let conn = self.conn.lock();
+        let data = conn.query_row(sql, params, |row| row.get(0)).optional()?;
+        Ok(data)
+    }
+
+    fn list<T>(&self, sql: &str, params: &[&ToSql]) -> BerylliumResult<Vec<T>>
+        where T: FromSql
+    {
+        let conn = self.conn.lock();
+        let mut stmt = conn.prepare(sql)?;
+        let rows = stmt.query_map(params, |row| row.get(0))?;
+        let mut values = vec![];
+        for value in rows {
+            values.push(value?);
+        }
+
+        Ok(values)
+    }
+
+    fn set(&self, sql: &str, params: &[&ToSql]) -> BerylliumResult<()> {
+        self.conn.lock().execute(sql, params)?;
+        Ok(())
+    }
+
+    /// Run the given function in a transaction, and commit if it succeeds.
+    fn transaction<F>(&self, call: F) -> BerylliumResult<()>
+        where F: FnOnce(&Transaction) -> BerylliumResult<()>
+    {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction()?;
+        call(&tx)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn load_sealed(&self, bot_id: &Uuid, kind: &str) -> BerylliumResult<Option<Vec<u8>>> {
+        self.get("SELECT data FROM sealed WHERE bot_id = ?1 AND kind = ?2",
+                 params![bot_id.to_string(), kind])
+    }
+
+    /// Save a blob which isn't JSON, and remove the rows it replaces.
+    fn save_sealed(&self, bot_id: &Uuid, kind: &str, data: &[u8],
+                   replaced: &[&str]) -> BerylliumResult<()> {
+        let id = bot_id.to_string();
+        self.transaction(|tx| {
+            for table in replaced {
+                tx.execute(&format!("DELETE FROM {} WHERE bot_id = ?1", table), params![id])?;
+            }
+
+            tx.execute("INSERT OR REPLACE INTO sealed (bot_id, kind, data) VALUES (?1, ?2, ?3)",
+                       params![id, kind, data])?;
+            Ok(())
+        })
+    }
+}
+
+impl BotStore for SqliteStore {
+    fn load_state(&self, bot_id: &Uuid) -> BerylliumResult<Option<Vec<u8>>> {
+        if let Some(data) = self.load_sealed(bot_id, "state")? {
+            return Ok(Some(data))
+        }
+
+        let id = bot_id.to_string();
+        let conn = self.conn.lock();
+        let state = conn.query_row(
+            "SELECT client, token, locale, ephemeral_timeout, origin_id, origin_name,
+                    origin_handle, origin_accent_id, conversation_id, conversation_name
+             FROM bots WHERE id = ?1", params![id], |row| {
+                let timeout: Option<i64> = row.get(3)?;
+                Ok(BotCreationData {
+                    id: *bot_id,
+                    client: row.get(0)?,
+                    token: row.get(1)?,
+                    locale: row.get(2)?,
+                    ephemeral_timeout: timeout.map(|t| cmp::max(t, 0) as u64),
+                    origin: Origin {
+                        id: get_uuid(row, 4)?,
+                        name: row.get(5)?,
+                        handle: row.get(6)?,
+                        accent_id: row.get(7)?,
+                    },
+                    conversation: Conversation {
+                        id: get_uuid(row, 8)?,
+                        name: row.get(9)?,
+                        members: HashSet::new(),
+                    },
+                })
+            }).optional()?;
+
+        let mut state = match state {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+
+        let mut stmt = conn.prepare("SELECT user_id, status FROM members WHERE bot_id = ?1")?;
+        let members = stmt.query_map(params![id], |row| Ok(Member {
+            id: get_uuid(row, 0)?,
+            status: row.get(1)?,
+        }))?;
+
+        for member in members {
+            state.conversation.members.insert(member?);
+        }
+
+        Ok(Some(serde_json::to_vec(&state)?))
+    }
+
+    fn save_state(&self, bot_id: &Uuid, data: &[u8]) -> BerylliumResult<()> {
+        let state: BotCreationData = match parse_json(data)? {
+            Some(s) => s,
+            None => return self.save_sealed(bot_id, "state", data, &["bots", "members"]),
+        };
+
+        let timeout = state.ephemeral_timeout.map(|t| cmp::min(t, i64::MAX as u64) as i64);
+        let id = bot_id.to_string();
+        self.transaction(|tx| {
+            tx.execute("DELETE FROM sealed WHERE bot_id = ?1 AND kind = 'state'", params![id])?;
+            tx.execute("INSERT OR REPLACE INTO bots (id, client, token, locale, ephemeral_timeout,
+                                                     origin_id, origin_name, origin_handle,
+                                                     origin_accent_id, conversation_id,
+                                                     conversation_name)
+                        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                       params![id, state.client, state.token, state.locale, timeout,
+                               state.origin.id.to_string(), state.origin.name,
+                               state.origin.handle, state.origin.accent_id,
+                               state.conversation.id.to_string(), state.conversation.name])?;
+            tx.execute("DELETE FROM members WHERE bot_id = ?1", params![id])?;
+            for member in &state.conversation.members {
+                tx.execute("INSERT INTO members (bot_id, user_id, status) VALUES (?1, ?2, ?3)",
+                           params![id, member.id.to_string(), member.status])?;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn load_devices(&self, bot_id: &Uuid) -> BerylliumResult<Option<Vec<u8>>> {
+        if let Some(data) = self.load_sealed(bot_id, "devices")? {
+            return Ok(Some(data))
+        }
+
+        let conn = self.conn.lock();
+        let mut stmt = conn.prepare("SELECT user_id, client_id FROM devices WHERE bot_id = ?1")?;
+        let rows = stmt.query_map(params![bot_id.to_string()], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+
+        let mut devices = HashMap::new();
+        for row in rows {
+            let (user, client) = row?;
+            devices.entry(user).or_insert_with(Vec::new).push(client);
+        }
+
+        if devices.is_empty() {
+            return Ok(None)
+        }
+
+        Ok(Some(serde_json::to_vec(&devices)?))
+    }
+
+    fn save_devices(&self, bot_id: &Uuid, data: &[u8]) -> BerylliumResult<()> {
+        let devices: HashMap<String, Vec<String>> = match parse_json(data)? {
+            Some(d) => d,
+            None => return self.save_sealed(bot_id, "devices", data, &["devices"]),
+        };
+
+        let id = bot_id.to_string();
+        self.transaction(|tx| {
+            tx.execute("DELETE FROM sealed WHERE bot_id = ?1 AND kind = 'devices'", params![id])?;
+            tx.execute("DELETE FROM devices WHERE bot_id = ?1", params![id])?;
+            for (user, clients) in &devices {
+                for client in clients {
+                    tx.execute("INSERT OR IGNORE INTO devices (bot_id, user_id, client_id)
+                                VALUES (?1, ?2, ?3)", params![id, user, client])?;
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    fn load_data(&self, bot_id: &Uuid, name: &str) -> BerylliumResult<Option<Vec<u8>>> {
+        self.get("SELECT data FROM bot_data WHERE bot_id = ?1 AND name = ?2",
+                 params![bot_id.to_string(), name])
+    }
+
+    fn save_data(&self, bot_id: &Uuid, name: &str, data: &[u8]) -> BerylliumResult<()> {
+        self.set("INSERT OR REPLACE INTO bot_data (bot_id, name, data) VALUES (?1, ?2, ?3)",
+                 params![bot_id.to_string(), name, data])
+    }
+
+    fn load_identity(&self, bot_id: &Uuid) -> BerylliumResult<Option<Vec<u8>>> {
+        self.get("SELECT identity FROM identities WHERE bot_id = ?1",
+                 params![bot_id.to_string()])
+    }
+
+    fn save_identity(&self, bot_id: &Uuid, data: &[u8]) -> BerylliumResult<()> {
+        self.set("INSERT OR REPLACE INTO identities (bot_id, identity) VALUES (?1, ?2)",
+                 params![bot_id.to_string(), data])
+    }
+
+    fn load_prekey(&self, bot_id: &Uuid, id: u16) -> BerylliumResult<Option<Vec<u8>>> {
+        self.get("SELECT prekey FROM prekeys WHERE bot_id = ?1 AND id = ?2",
+                 params![bot_id.to_string(), id as i64])
+    }
+
+    fn save_prekey(&self, bot_id: &Uuid, id: u16, data: &[u8]) -> BerylliumResult<()> {
+        self.set("INSERT OR REPLACE INTO prekeys (bot_id, id, prekey) VALUES (?1, ?2, ?3)",
+                 params![bot_id.to_string(), id as i64, data])
+    }
+
+    fn delete_prekey(&self, bot_id: &Uuid, id: u16) -> BerylliumResult<()> {
+        self.set("DELETE FROM prekeys WHERE bot_id = ?1 AND id = ?2",
+                 params![bot_id.to_string(), id as i64])
+    }
+
+    fn load_session(&self, bot_id: &Uuid, session_id: &str) -> BerylliumResult<Option<Vec<u8>>> {
+        self.get("SELECT session FROM sessions WHERE bot_id = ?1 AND session_id = ?2",
+                 params![bot_id.to_string(), session_id])
+    }
+
+    fn save_session(&self, bot_id: &Uuid, session_id: &str, data: &[u8]) -> BerylliumResult<()> {
+        self.set("INSERT OR REPLACE INTO sessions (bot_id, session_id, session) VALUES (?1, ?2, ?3)",
+                 params![bot_id.to_string(), session_id, data])
+    }
+
+    fn list_bots(&self) -> BerylliumResult<Vec<Uuid>> {
+        let ids: Vec<String> = self.list("SELECT id FROM bots
+                                          UNION SELECT bot_id FROM sealed WHERE kind = 'state'
+                                          UNION SELECT bot_id FROM identities", params![])?;
+        Ok(ids.iter().filter_map(|id| id.parse().ok()).collect())
+    }
+
+    fn list_data(&self, bot_id: &Uuid) -> BerylliumResult<Vec<String>> {
+        self.list("SELECT name FROM bot_data WHERE bot_id = ?1", params![bot_id.to_string()])
+    }
+
+    fn list_prekeys(&self, bot_id: &Uuid) -> BerylliumResult<Vec<u16>> {
+        let ids: Vec<i64> = self.list("SELECT id FROM prekeys WHERE bot_id = ?1",
+                                      params![bot_id.to_string()])?;
+        ids.into_iter().map(|id| {
+            if id < 0 || id > u16::MAX as i64 {
+                return Err(BerylliumError::Other(format!("Invalid prekey ID {} for bot {}",
+                                                         id, bot_id)))
+            }
+
+            Ok(id as u16)
+        }).collect()
+    }
+
+    fn list_sessions(&self, bot_id: &Uuid) -> BerylliumResult<Vec<String>> {
+        self.list("SELECT session_id FROM sessions WHERE bot_id = ?1",
+                  params![bot_id.to_string()])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+    use std::collections::HashMap;
+    use super::SqliteStore;
+    use storage::{BotStore, bot_ids};
+    use types::BotCreationData;
+    use uuid::Uuid;
+
+    const STATE: &'static str = r#"{
+        "id": "BOT_ID",
+        "client": "c0ffee",
+        "origin": {
+            "id": "7b8d4100-5c5e-11e8-9c2d-fa7ae01bbebc",
+            "name": "Alice",
+            "handle": "alice",
+            "accent_id": 3
+        },
+        "conversation": {
+            "id": "7b8d4008-5c5e-11e8-9c2d-fa7ae01bbebc",
+            "name": "Chat",
+            "members": [
+                { "id": "7b8d4100-5c5e-11e8-9c2d-fa7ae01bbebc", "status": 0 },
+                { "id": "7b8d4288-5c5e-11e8-9c2d-fa7ae01bbebc", "status": 1 }
+            ]
+        },
+        "token": "foobar",
+        "locale": "en_US",
+        "ephemeral_timeout": 5000
+    }"#;
+
+    fn state(id: &Uuid) -> Vec<u8> {
+        STATE.replace("BOT_ID", &id.to_string()).into_bytes()
+    }
+
+    #[test]
+    fn test_state_round_trip() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        let (id, _) = bot_ids();
+        assert!(store.load_state(&id).unwrap().is_none());
+        store.save_state(&id, &state(&id)).unwrap();
+
+        let bytes = store.load_state(&id).unwrap().unwrap();
+        let state: BotCreationData = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(state.id, id);
+        assert_eq!(state.client, "c0ffee");
+        assert_eq!(state.token, "foobar");
+        assert_eq!(state.ephemeral_timeout, Some(5000));
+        assert_eq!(state.origin.handle, "alice");
+        assert_eq!(state.origin.accent_id, 3);
+        assert_eq!(state.conversation.name, "Chat");
+        assert_eq!(state.conversation.members.len(), 2);
+        assert_eq!(store.list_bots().unwrap(), vec![id]);
+
+        // Blobs that we can't parse replace the rows.
+        store.save_state(&id, b"BEnc\x01sealed").unwrap();
+        assert_eq!(store.load_state(&id).unwrap().unwrap(), b"BEnc\x01sealed");
+        assert_eq!(store.list_bots().unwrap(), vec![id]);
+        store.save_state(&id, &state(&id)).unwrap();
+        assert!(serde_json::from_slice::<BotCreationData>(
+            &store.load_state(&id).unwrap().unwrap()).is_ok());
+
+        // JSON which doesn't match the state is an error (and it changes nothing).
+        assert!(store.save_state(&id, br#"{"id": "foo"}"#).is_err());
+        assert!(store.save_devices(&id, br#"{"alice": "a1"}"#).is_err());
+        let bytes = store.load_state(&id).unwrap().unwrap();
+        let state: BotCreationData = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(state.conversation.members.len(), 2);
+    }
+
+    #[test]
+    fn test_devices_round_trip() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        let (id, _) = bot_ids();
+        assert!(store.load_devices(&id).unwrap().is_none());
+
+        let mut devices = HashMap::new();
+        devices.insert(String::from("alice"), vec![String::from("a1"), String::from("a2")]);
+        devices.insert(String::from("bob"), vec![String::from("b1")]);
+        store.save_devices(&id, &serde_json::to_vec(&devices).unwrap()).unwrap();
+
+        let bytes = store.load_devices(&id).unwrap().unwrap();
+        let mut loaded: HashMap<String, Vec<String>> = serde_json::from_slice(&bytes).unwrap();
+        loaded.get_mut("alice").unwrap().sort();
+        assert_eq!(loaded, devices);
+    }
+}
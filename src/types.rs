@@ -1,18 +1,29 @@
+use utils;
+use chrono::{DateTime, Utc};
 use errors::{BerylliumError, BerylliumResult};
 use futures::Future;
 use hyper::Client;
 use hyper::header::ContentType;
 use hyper_rustls::HttpsConnector;
 use image::{self, GenericImage, ImageFormat as ImgFormat};
-use mime::{IMAGE_BMP, IMAGE_GIF};
+use messages_proto::{Asset, Asset_Original, Asset_RemoteData, LinkPreview as ProtoLinkPreview};
+use messages_proto::{Asset_AudioMetaData, Asset_ImageMetaData, Asset_VideoMetaData};
+use messages_proto::{Location as ProtoLocation, Mention as ProtoMention, Text};
+use messages_proto::{Quote as ProtoQuote, Tweet as ProtoTweet};
+use messages_proto::{Availability_Type, Button, Composite, Composite_Item};
+use mime::{IMAGE_BMP, IMAGE_GIF, Mime};
+use protobuf::ProtobufEnum;
 use serde::de::{Deserialize, Deserializer, Error as DecodeError};
 use serde_json::Value;
 use std::borrow::Borrow;
+use std::cmp;
 use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::hash::{Hash, Hasher};
 use std::io::Read;
 use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 use uuid::Uuid;
 
 // FIXME: Check the types (for example, id should be Uuid instead of String),
@@ -37,8 +48,76 @@ pub enum Event {
     Message {
         text: String,
         from: String,       // FIXME: Should be `Uuid`
+        mentions: Vec<Mention>,
+        link_previews: Vec<LinkPreview>,
+        /// Message quoted by this text (if this is a reply).
+        quote: Option<Quote>,
+    },
+    /// An image, video, audio or any other file shared in the conversation.
+    Asset {
+        asset: AssetInfo,
+        from: String,
+    },
+    /// A message has been edited (i.e., replaced by this new message).
+    MessageEdited {
+        replacing_message_id: String,
+        text: String,
+        from: String,
+    },
+    /// A message has been deleted for everyone in the conversation.
+    MessageDeleted {
+        message_id: String,
+        from: String,
+    },
+    /// A message has been hidden (i.e., deleted only for the user).
+    MessageHidden {
+        conversation_id: String,
+        message_id: String,
+        from: String,
+    },
+    /// A user has pinged the conversation. A hot knock is a
+    /// repeated ping (which is louder in Wire clients).
+    Knock {
+        hot: bool,
+        from: String,
+    },
+    /// A location shared in the conversation.
+    Location {
+        location: Location,
+        from: String,
+    },
+    /// Message(s) have been delivered to (or read by) a user.
+    Confirmation {
+        message_id: String,
+        /// Other messages confirmed along with the first one.
+        more_message_ids: Vec<String>,
+        kind: ConfirmationType,
+        from: String,
+    },
+    /// A user has reacted to a message. An empty emoji means that
+    /// the user has removed their previous reaction.
+    Reaction {
+        message_id: String,
+        emoji: String,
+        from: String,
+    },
+    /// A user has changed their availability status.
+    Availability {
+        status: AvailabilityStatus,
+        from: String,
+    },
+    /// A message composed of texts and buttons (for example, a poll).
+    Composite {
+        items: Vec<CompositeItem>,
+        from: String,
+    },
+    /// A user has clicked a button in a composite message sent by this bot.
+    /// The bot automatically lets the user know that the click has been received.
+    ButtonAction {
+        reference_message_id: String,
+        button_id: String,
+        from: String,
     },
-    Image,
 }
 
 /// Event data passed to the type implementing the `Handler` trait.
@@ -47,10 +126,708 @@ pub struct EventData {
     pub bot_id: Uuid,
     /// Conversation data
     pub conversation: Conversation,
+    /// ID of the message which triggered this event (only for message events).
+    pub message_id: Option<String>,
+    /// Client (device) ID of the sender (only for message events).
+    pub sender_client: Option<String>,
+    /// Time at which the event occurred.
+    pub time: DateTime<Utc>,
+    /// Time after which the message should expire (only for ephemeral messages).
+    pub expires_after: Option<Duration>,
+    /// Whether the sender expects a read confirmation for this message
+    /// (see `BotClient::mark_read`).
+    pub expects_read_confirmation: bool,
     /// Event-type and related data (if any)
     pub event: Event,
 }
 
+/// A user mentioned in a text message. The offsets are in UTF-16 code units
+/// (which is how Wire clients count the characters in a text).
+#[derive(Clone, Debug)]
+pub struct Mention {
+    pub user_id: Uuid,
+    pub start: usize,
+    pub length: usize,
+}
+
+impl Mention {
+    /// Returns `None` if the mention doesn't refer to a valid user.
+    pub fn from_proto(mut mention: ProtoMention) -> Option<Mention> {
+        if !mention.has_user_id() || mention.get_start() < 0 || mention.get_length() < 0 {
+            return None
+        }
+
+        mention.take_user_id().parse().ok().map(|id| Mention {
+            user_id: id,
+            start: mention.get_start() as usize,
+            length: mention.get_length() as usize,
+        })
+    }
+}
+
+impl From<Mention> for ProtoMention {
+    fn from(mention: Mention) -> ProtoMention {
+        let mut m = ProtoMention::new();
+        m.set_start(mention.start as i32);
+        m.set_length(mention.length as i32);
+        m.set_user_id(mention.user_id.to_string());
+        m
+    }
+}
+
+/// Reference to the message quoted in a reply.
+#[derive(Clone, Debug)]
+pub struct Quote {
+    pub message_id: String,
+    /// SHA-256 hash of the quoted message (if any).
+    pub sha256: Option<Vec<u8>>,
+}
+
+impl Quote {
+    /// Check whether this quote refers to the given text (sent at the given time).
+    pub fn matches(&self, text: &str, time: &DateTime<Utc>) -> bool {
+        match self.sha256 {
+            Some(ref hash) => *hash == utils::text_hash(text, time),
+            None => false,
+        }
+    }
+}
+
+impl From<ProtoQuote> for Quote {
+    fn from(mut quote: ProtoQuote) -> Quote {
+        Quote {
+            message_id: quote.take_quoted_message_id(),
+            sha256: if quote.has_quoted_message_sha256() {
+                Some(quote.take_quoted_message_sha256())
+            } else {
+                None
+            },
+        }
+    }
+}
+
+/// Preview of a link in a text message.
+#[derive(Clone, Debug)]
+pub struct LinkPreview {
+    pub url: String,
+    /// Offset of the URL from the beginning of the text (in UTF-16 code units).
+    pub url_offset: usize,
+    pub permanent_url: Option<String>,
+    pub title: Option<String>,
+    pub summary: Option<String>,
+    pub tweet: Option<Tweet>,
+    /// Preview image which has already been uploaded (for example, the image
+    /// of an incoming preview which is being forwarded). New images can be
+    /// attached to outgoing previews using `RichText::link_preview`.
+    pub image: Option<AssetInfo>,
+}
+
+/// Additional metadata for links to tweets.
+#[derive(Clone, Debug)]
+pub struct Tweet {
+    pub author: Option<String>,
+    pub username: Option<String>,
+}
+
+impl LinkPreview {
+    pub fn new(url: &str, url_offset: usize) -> Self {
+        LinkPreview {
+            url: url.to_owned(),
+            url_offset: url_offset,
+            permanent_url: None,
+            title: None,
+            summary: None,
+            tweet: None,
+            image: None,
+        }
+    }
+}
+
+impl From<ProtoLinkPreview> for LinkPreview {
+    fn from(mut preview: ProtoLinkPreview) -> LinkPreview {
+        // Older clients send the metadata in the (deprecated) article.
+        let mut article = preview.take_article();
+        macro_rules! field {
+            ($has:ident, $take:ident) => {
+                if preview.$has() {
+                    Some(preview.$take())
+                } else if article.$has() {
+                    Some(article.$take())
+                } else {
+                    None
+                }
+            };
+        }
+
+        LinkPreview {
+            url_offset: cmp::max(preview.get_url_offset(), 0) as usize,
+            permanent_url: field!(has_permanent_url, take_permanent_url),
+            title: field!(has_title, take_title),
+            summary: field!(has_summary, take_summary),
+            image: field!(has_image, take_image).map(AssetInfo::from),
+            tweet: if preview.has_tweet() {
+                let mut tweet = preview.take_tweet();
+                Some(Tweet {
+                    author: if tweet.has_author() { Some(tweet.take_author()) } else { None },
+                    username: if tweet.has_username() { Some(tweet.take_username()) } else { None },
+                })
+            } else {
+                None
+            },
+            url: preview.take_url(),
+        }
+    }
+}
+
+/// Text message with rich content (mentions, link previews and quotes).
+///
+/// ```rust,ignore
+/// let text = RichText::new().text("Hey ").mention(&user_id, "waffles").text("!");
+/// client.send_rich_text(text);
+/// ```
+#[derive(Clone, Default)]
+pub struct RichText {
+    content: String,
+    mentions: Vec<Mention>,
+    link_previews: Vec<(LinkPreview, Option<Arc<Image>>)>,
+    quote: Option<Quote>,
+}
+
+impl RichText {
+    pub fn new() -> Self {
+        RichText::default()
+    }
+
+    /// Append some plain text.
+    pub fn text(mut self, text: &str) -> Self {
+        self.content.push_str(text);
+        self
+    }
+
+    /// Mention a user with the given name (which gets prefixed with `@`).
+    pub fn mention(mut self, user_id: &Uuid, name: &str) -> Self {
+        let name = format!("@{}", name);
+        self.mentions.push(Mention {
+            user_id: *user_id,
+            start: self.content.encode_utf16().count(),
+            length: name.encode_utf16().count(),
+        });
+
+        self.content.push_str(&name);
+        self
+    }
+
+    /// Attach a preview for a link in the text, along with an image (if any)
+    /// which will be uploaded while sending the message. This image replaces
+    /// the already uploaded image of the preview (if any).
+    pub fn link_preview(mut self, preview: LinkPreview, image: Option<Arc<Image>>) -> Self {
+        self.link_previews.push((preview, image));
+        self
+    }
+
+    /// Reply to (i.e., quote) a text message with the given ID, text and time.
+    /// The text and time are used for computing the hash of the quoted message,
+    /// which clients use for verifying the quote.
+    pub fn quote(mut self, message_id: &str, text: &str, time: &DateTime<Utc>) -> Self {
+        self.quote = Some(Quote {
+            message_id: message_id.to_owned(),
+            sha256: Some(utils::text_hash(text, time)),
+        });
+
+        self
+    }
+
+    pub fn content(&self) -> &str {
+        &self.content
+    }
+
+    pub fn mentions(&self) -> &[Mention] {
+        &self.mentions
+    }
+
+    /// Images (if any) for each of the link previews.
+    pub fn preview_images(&self) -> Vec<Option<Arc<Image>>> {
+        self.link_previews.iter().map(|&(_, ref img)| img.clone()).collect()
+    }
+}
+
+/// NOTE: This doesn't include the images of link previews (which should be uploaded).
+impl From<RichText> for Text {
+    fn from(text: RichText) -> Text {
+        let mut txt = Text::new();
+        txt.set_content(text.content);
+        for mention in text.mentions {
+            txt.mut_mentions().push(mention.into());
+        }
+
+        for (preview, _) in text.link_previews {
+            let mut p = ProtoLinkPreview::new();
+            p.set_url(preview.url);
+            p.set_url_offset(preview.url_offset as i32);
+            if let Some(url) = preview.permanent_url {
+                p.set_permanent_url(url);
+            }
+
+            if let Some(title) = preview.title {
+                p.set_title(title);
+            }
+
+            if let Some(summary) = preview.summary {
+                p.set_summary(summary);
+            }
+
+            if let Some(image) = preview.image {
+                p.set_image(image.into());
+            }
+
+            if let Some(tweet) = preview.tweet {
+                let mut t = ProtoTweet::new();
+                if let Some(author) = tweet.author {
+                    t.set_author(author);
+                }
+
+                if let Some(username) = tweet.username {
+                    t.set_username(username);
+                }
+
+                p.set_tweet(t);
+            }
+
+            txt.mut_link_preview().push(p);
+        }
+
+        if let Some(quote) = text.quote {
+            let mut q = ProtoQuote::new();
+            q.set_quoted_message_id(quote.message_id);
+            if let Some(hash) = quote.sha256 {
+                q.set_quoted_message_sha256(hash);
+            }
+
+            txt.set_quote(q);
+        }
+
+        txt
+    }
+}
+
+/// Type of the confirmation for a message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfirmationType {
+    Delivered,
+    Read,
+}
+
+/// Availability status of a user.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AvailabilityStatus {
+    None,
+    Available,
+    Away,
+    Busy,
+}
+
+impl AvailabilityStatus {
+    /// Convert the status from the message. Unknown statuses (from newer clients)
+    /// are treated as `None`.
+    pub fn from_proto(status: i32) -> AvailabilityStatus {
+        match Availability_Type::from_i32(status) {
+            Some(Availability_Type::NONE) => AvailabilityStatus::None,
+            Some(Availability_Type::AVAILABLE) => AvailabilityStatus::Available,
+            Some(Availability_Type::AWAY) => AvailabilityStatus::Away,
+            Some(Availability_Type::BUSY) => AvailabilityStatus::Busy,
+            None => {
+                info!("Unknown availability status {}", status);
+                AvailabilityStatus::None
+            },
+        }
+    }
+}
+
+/// An item in a composite message.
+#[derive(Clone, Debug)]
+pub enum CompositeItem {
+    Text {
+        content: String,
+        mentions: Vec<Mention>,
+    },
+    Button {
+        id: String,
+        text: String,
+    },
+}
+
+impl CompositeItem {
+    /// Convert the item, ignoring it if it's empty (or has unknown content).
+    pub fn from_proto(mut item: Composite_Item) -> Option<CompositeItem> {
+        if item.has_text() {
+            let mut text = item.take_text();
+            Some(CompositeItem::Text {
+                mentions: text.take_mentions().into_vec().into_iter()
+                              .filter_map(Mention::from_proto).collect(),
+                content: text.take_content(),
+            })
+        } else if item.has_button() {
+            let mut button = item.take_button();
+            Some(CompositeItem::Button {
+                id: button.take_id(),
+                text: button.take_text(),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+impl From<CompositeItem> for Composite_Item {
+    fn from(item: CompositeItem) -> Composite_Item {
+        let mut proto_item = Composite_Item::new();
+        match item {
+            CompositeItem::Text { content, mentions } => {
+                let mut txt = Text::new();
+                txt.set_content(content);
+                for mention in mentions {
+                    txt.mut_mentions().push(mention.into());
+                }
+
+                proto_item.set_text(txt);
+            },
+            CompositeItem::Button { id, text } => {
+                let mut button = Button::new();
+                button.set_id(id);
+                button.set_text(text);
+                proto_item.set_button(button);
+            },
+        }
+
+        proto_item
+    }
+}
+
+/// Builder for a message with texts and buttons.
+#[derive(Clone, Debug, Default)]
+pub struct CompositeMessage {
+    items: Vec<CompositeItem>,
+}
+
+impl CompositeMessage {
+    pub fn new() -> Self {
+        CompositeMessage::default()
+    }
+
+    /// Add some text to the message.
+    pub fn text(mut self, text: &str) -> Self {
+        self.items.push(CompositeItem::Text {
+            content: text.to_owned(),
+            mentions: vec![],
+        });
+
+        self
+    }
+
+    /// Add a text with mentions to the message (link previews and quotes are ignored).
+    pub fn rich_text(mut self, text: RichText) -> Self {
+        self.items.push(CompositeItem::Text {
+            content: text.content,
+            mentions: text.mentions,
+        });
+
+        self
+    }
+
+    /// Add a button to the message. The ID should be unique among the buttons
+    /// in this message, because that's what we get when a user clicks it.
+    pub fn button(mut self, id: &str, text: &str) -> Self {
+        self.items.push(CompositeItem::Button {
+            id: id.to_owned(),
+            text: text.to_owned(),
+        });
+
+        self
+    }
+
+    /// Items in this message (in the order they were added).
+    pub fn items(&self) -> &[CompositeItem] {
+        &self.items
+    }
+}
+
+impl From<CompositeMessage> for Composite {
+    fn from(message: CompositeMessage) -> Composite {
+        let mut composite = Composite::new();
+        for item in message.items {
+            composite.mut_items().push(item.into());
+        }
+
+        composite
+    }
+}
+
+/// Location shared in a conversation.
+#[derive(Clone, Debug)]
+pub struct Location {
+    pub latitude: f32,
+    pub longitude: f32,
+    /// Description (or name) of the location.
+    pub name: Option<String>,
+    /// Zoom level (as in Google Maps).
+    pub zoom: Option<i32>,
+}
+
+impl From<ProtoLocation> for Location {
+    fn from(mut location: ProtoLocation) -> Location {
+        Location {
+            latitude: location.get_latitude(),
+            longitude: location.get_longitude(),
+            name: if location.has_name() { Some(location.take_name()) } else { None },
+            zoom: if location.has_zoom() { Some(location.get_zoom()) } else { None },
+        }
+    }
+}
+
+impl From<Location> for ProtoLocation {
+    fn from(location: Location) -> ProtoLocation {
+        let mut loc = ProtoLocation::new();
+        loc.set_latitude(location.latitude);
+        loc.set_longitude(location.longitude);
+        if let Some(name) = location.name {
+            loc.set_name(name);
+        }
+
+        if let Some(zoom) = location.zoom {
+            loc.set_zoom(zoom);
+        }
+
+        loc
+    }
+}
+
+/// Asset shared in a conversation. Wire clients usually send two messages
+/// (with the same message ID) for an asset - one with the original metadata
+/// (before uploading) and another with the remote data (after uploading).
+#[derive(Clone, Debug)]
+pub struct AssetInfo {
+    /// Metadata of the original (unencrypted) asset.
+    pub original: Option<AssetOriginal>,
+    /// Location and keys of the uploaded (encrypted) asset.
+    pub remote: Option<RemoteAsset>,
+}
+
+/// Metadata of the original asset.
+#[derive(Clone, Debug)]
+pub struct AssetOriginal {
+    pub mime_type: String,
+    pub size: u64,
+    pub name: Option<String>,
+    pub metadata: Option<AssetMetaData>,
+    /// Link to the source of the asset (for example, GIPHY).
+    pub source: Option<String>,
+    /// Caption of the asset (for example, the search query used for finding it).
+    pub caption: Option<String>,
+}
+
+/// Additional metadata for images, videos and audio files.
+#[derive(Clone, Debug)]
+pub enum AssetMetaData {
+    Image {
+        width: i32,
+        height: i32,
+        tag: Option<String>,
+    },
+    Video {
+        width: Option<i32>,
+        height: Option<i32>,
+        duration_in_millis: Option<u64>,
+    },
+    Audio {
+        duration_in_millis: Option<u64>,
+        /// Each byte represents a loudness value (0 - 255) in chronological order.
+        normalized_loudness: Option<Vec<u8>>,
+    },
+}
+
+/// Data required for downloading and decrypting an uploaded asset.
+#[derive(Clone, Debug)]
+pub struct RemoteAsset {
+    pub asset_id: Option<String>,
+    pub asset_token: Option<String>,
+    /// AES-256 key used for encrypting the asset.
+    pub otr_key: Vec<u8>,
+    /// SHA-256 hash of the encrypted asset.
+    pub sha256: Vec<u8>,
+}
+
+impl From<Asset> for AssetInfo {
+    fn from(mut asset: Asset) -> AssetInfo {
+        AssetInfo {
+            original: if asset.has_original() {
+                Some(AssetOriginal::from(asset.take_original()))
+            } else {
+                None
+            },
+            remote: if asset.has_uploaded() {
+                Some(RemoteAsset::from(asset.take_uploaded()))
+            } else {
+                None
+            },
+        }
+    }
+}
+
+impl From<AssetInfo> for Asset {
+    fn from(info: AssetInfo) -> Asset {
+        let mut asset = Asset::new();
+        if let Some(original) = info.original {
+            asset.set_original(original.into());
+        }
+
+        if let Some(remote) = info.remote {
+            asset.set_uploaded(remote.into());
+        }
+
+        asset
+    }
+}
+
+impl From<Asset_Original> for AssetOriginal {
+    fn from(mut original: Asset_Original) -> AssetOriginal {
+        let metadata = if original.has_image() {
+            let mut image = original.take_image();
+            Some(AssetMetaData::Image {
+                width: image.get_width(),
+                height: image.get_height(),
+                tag: if image.has_tag() { Some(image.take_tag()) } else { None },
+            })
+        } else if original.has_video() {
+            let video = original.take_video();
+            Some(AssetMetaData::Video {
+                width: if video.has_width() { Some(video.get_width()) } else { None },
+                height: if video.has_height() { Some(video.get_height()) } else { None },
+                duration_in_millis: if video.has_duration_in_millis() {
+                    Some(video.get_duration_in_millis())
+                } else {
+                    None
+                },
+            })
+        } else if original.has_audio() {
+            let mut audio = original.take_audio();
+            Some(AssetMetaData::Audio {
+                duration_in_millis: if audio.has_duration_in_millis() {
+                    Some(audio.get_duration_in_millis())
+                } else {
+                    None
+                },
+                normalized_loudness: if audio.has_normalized_loudness() {
+                    Some(audio.take_normalized_loudness())
+                } else {
+                    None
+                },
+            })
+        } else {
+            None
+        };
+
+        AssetOriginal {
+            mime_type: original.take_mime_type(),
+            size: original.get_size(),
+            name: if original.has_name() { Some(original.take_name()) } else { None },
+            metadata,
+            source: if original.has_source() { Some(original.take_source()) } else { None },
+            caption: if original.has_caption() { Some(original.take_caption()) } else { None },
+        }
+    }
+}
+
+impl From<AssetOriginal> for Asset_Original {
+    fn from(original: AssetOriginal) -> Asset_Original {
+        let mut proto = Asset_Original::new();
+        proto.set_mime_type(original.mime_type);
+        proto.set_size(original.size);
+        if let Some(name) = original.name {
+            proto.set_name(name);
+        }
+
+        match original.metadata {
+            Some(AssetMetaData::Image { width, height, tag }) => {
+                let mut image = Asset_ImageMetaData::new();
+                image.set_width(width);
+                image.set_height(height);
+                if let Some(tag) = tag {
+                    image.set_tag(tag);
+                }
+
+                proto.set_image(image);
+            },
+            Some(AssetMetaData::Video { width, height, duration_in_millis }) => {
+                let mut video = Asset_VideoMetaData::new();
+                if let Some(width) = width {
+                    video.set_width(width);
+                }
+
+                if let Some(height) = height {
+                    video.set_height(height);
+                }
+
+                if let Some(millis) = duration_in_millis {
+                    video.set_duration_in_millis(millis);
+                }
+
+                proto.set_video(video);
+            },
+            Some(AssetMetaData::Audio { duration_in_millis, normalized_loudness }) => {
+                let mut audio = Asset_AudioMetaData::new();
+                if let Some(millis) = duration_in_millis {
+                    audio.set_duration_in_millis(millis);
+                }
+
+                if let Some(loudness) = normalized_loudness {
+                    audio.set_normalized_loudness(loudness);
+                }
+
+                proto.set_audio(audio);
+            },
+            None => (),
+        }
+
+        if let Some(source) = original.source {
+            proto.set_source(source);
+        }
+
+        if let Some(caption) = original.caption {
+            proto.set_caption(caption);
+        }
+
+        proto
+    }
+}
+
+impl From<Asset_RemoteData> for RemoteAsset {
+    fn from(mut data: Asset_RemoteData) -> RemoteAsset {
+        RemoteAsset {
+            asset_id: if data.has_asset_id() { Some(data.take_asset_id()) } else { None },
+            asset_token: if data.has_asset_token() { Some(data.take_asset_token()) } else { None },
+            otr_key: data.take_otr_key(),
+            sha256: data.take_sha256(),
+        }
+    }
+}
+
+impl From<RemoteAsset> for Asset_RemoteData {
+    fn from(remote: RemoteAsset) -> Asset_RemoteData {
+        let mut data = Asset_RemoteData::new();
+        data.set_otr_key(remote.otr_key);
+        data.set_sha256(remote.sha256);
+        if let Some(id) = remote.asset_id {
+            data.set_asset_id(id);
+        }
+
+        if let Some(token) = remote.asset_token {
+            data.set_asset_token(token);
+        }
+
+        data
+    }
+}
+
 /// Represents a conversation member.
 #[derive(Clone, Deserialize, Serialize)]
 pub struct Member {
@@ -103,12 +880,56 @@ pub struct BotCreationData {
     pub conversation: Conversation,
     pub token: String,
     pub locale: String,
+    /// Default expiry (in milliseconds) for the messages sent by the bot.
+    /// This isn't a part of the creation request - it's set by the bot.
+    #[serde(default)]
+    pub ephemeral_timeout: Option<u64>,
 }
 
+/// Devices known to the bot, or the mismatch reported by the server
+/// when sending a message.
 #[derive(Default, Deserialize)]
 pub struct Devices {
     // UserID -> [ClientID]
+    #[serde(default)]
     pub missing: HashMap<String, Vec<String>>,
+    /// Devices which aren't part of the conversation anymore.
+    #[serde(default)]
+    pub redundant: HashMap<String, Vec<String>>,
+    /// Devices which have been deleted by their users.
+    #[serde(default)]
+    pub deleted: HashMap<String, Vec<String>>,
+}
+
+impl Devices {
+    /// Remove the given clients (of each user) from the missing (i.e., known) devices.
+    /// Returns `true` if any of those were removed.
+    pub fn remove_clients(&mut self, clients: &HashMap<String, Vec<String>>) -> bool {
+        let mut changed = false;
+        for (user_id, user_clients) in clients {
+            let is_empty = match self.missing.get_mut(user_id) {
+                Some(known) => {
+                    let count = known.len();
+                    known.retain(|c| !user_clients.contains(c));
+                    changed |= known.len() != count;
+                    known.is_empty()
+                },
+                None => continue,
+            };
+
+            if is_empty {
+                self.missing.remove(user_id);
+            }
+        }
+
+        changed
+    }
+
+    /// Forget the devices which are reported as redundant or deleted.
+    pub fn remove_stale(&mut self, report: &Devices) -> bool {
+        let redundant = self.remove_clients(&report.redundant);
+        self.remove_clients(&report.deleted) || redundant
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -143,6 +964,9 @@ pub enum ConversationData {
         sender: String,
         recipient: String,
         text: String,
+        /// Base64-encoded external data (for large messages)
+        #[serde(default)]
+        data: Option<String>,
     },
     LeavingOrJoiningMembers {
         user_ids: Vec<Uuid>,
@@ -160,7 +984,24 @@ pub struct MessageData {
     pub conversation: String,
     pub from: String,
     pub data: ConversationData,
-    pub time: String,
+    #[serde(default = "Utc::now", deserialize_with = "deserialize_time")]
+    pub time: DateTime<Utc>,
+}
+
+/// Parse the event time. If it's malformed, then we use the time at which we've
+/// received the event, so that the event is still handled.
+fn deserialize_time<'de, D>(de: D) -> Result<DateTime<Utc>, D::Error>
+    where D: Deserializer<'de>
+{
+    let deser_result: Value = Deserialize::deserialize(de)?;
+    if let Value::String(ref s) = deser_result {
+        if let Ok(time) = s.parse() {
+            return Ok(time)
+        }
+    }
+
+    info!("Invalid event time: {}", deser_result);
+    Ok(Utc::now())
 }
 
 #[derive(Deserialize, Serialize)]
@@ -181,6 +1022,9 @@ pub struct BotCreationResponse {
 pub struct MessageRequest<'a, 'b> {
     pub sender: &'a str,
     pub recipients: HashMap<&'b str, HashMap<&'b str, String>>,
+    /// Base64-encoded external data (if the message is too large).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -196,7 +1040,8 @@ pub struct AssetData {
 }
 
 pub enum MessageStatus {
-    Sent,
+    /// The message has been sent (the server may still report some stale devices).
+    Sent(Devices),
     Failed(Devices),
 }
 
@@ -305,3 +1150,312 @@ impl Into<ContentType> for ImageFormat {
         }
     }
 }
+
+/// Number of loudness values computed for an audio file.
+const LOUDNESS_LEVELS: usize = 100;
+
+#[derive(Clone)]
+/// Represents an audio file along with the metadata required by Wire clients.
+pub struct Audio {
+    mime: Mime,
+    meta: AudioMeta,
+    data: Vec<u8>,
+}
+
+#[derive(Clone)]
+/// Metadata required for an audio file to be rendered in Wire.
+pub struct AudioMeta {
+    pub duration_in_millis: u64,
+    /// Each byte represents a loudness value (0 - 255) in chronological order.
+    pub normalized_loudness: Option<Vec<u8>>,
+}
+
+impl Audio {
+    /// Create an audio asset (of any format) with known metadata.
+    pub fn new(data: Vec<u8>, mime: Mime, meta: AudioMeta) -> Self {
+        Audio {
+            mime: mime,
+            meta: meta,
+            data: data,
+        }
+    }
+
+    /// Read a WAV file (with 8 or 16-bit PCM samples) from the given bytes,
+    /// and compute its duration and the normalized loudness waveform.
+    pub fn from_wav(bytes: Vec<u8>) -> BerylliumResult<Self> {
+        let wav = WavData::parse(&bytes)?;
+        let samples = wav.samples();
+        let frames = samples.len() / wav.channels as usize;
+        let duration = (frames as u64 * 1000) / wav.sample_rate as u64;
+
+        // Merge the channels by averaging their amplitudes.
+        let amplitudes = samples.chunks(wav.channels as usize).map(|frame| {
+            frame.iter().fold(0.0f32, |acc, s| acc + s.abs()) / frame.len() as f32
+        }).collect::<Vec<_>>();
+
+        Ok(Audio {
+            mime: "audio/wav".parse().unwrap(),
+            meta: AudioMeta {
+                duration_in_millis: duration,
+                normalized_loudness: Some(utils::normalized_loudness(&amplitudes,
+                                                                     LOUDNESS_LEVELS)),
+            },
+            data: bytes,
+        })
+    }
+
+    pub fn mime(&self) -> &Mime {
+        &self.mime
+    }
+
+    pub fn metadata(&self) -> &AudioMeta {
+        &self.meta
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+/// PCM data from a WAV file.
+struct WavData<'a> {
+    channels: u16,
+    sample_rate: u32,
+    bits_per_sample: u16,
+    data: &'a [u8],
+}
+
+impl<'a> WavData<'a> {
+    fn parse(bytes: &'a [u8]) -> BerylliumResult<Self> {
+        let invalid = || BerylliumError::Other(String::from("Invalid or unsupported WAV file"));
+        if bytes.len() < 12 || &bytes[..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+            return Err(invalid())
+        }
+
+        let (mut format, mut data) = (None, None);
+        let mut rest = &bytes[12..];
+        // Each chunk has a 4-byte ID, followed by its size (little endian)
+        // and the chunk data (padded to an even length).
+        while rest.len() >= 8 {
+            let size = utils::read_u32_le(&rest[4..8]) as usize;
+            let chunk = &rest[8..];
+            if chunk.len() < size {
+                return Err(invalid())
+            }
+
+            if &rest[..4] == b"fmt " {
+                format = Some(&chunk[..size]);
+            } else if &rest[..4] == b"data" {
+                data = Some(&chunk[..size]);
+            }
+
+            let padded = size + size % 2;
+            rest = if chunk.len() > padded { &chunk[padded..] } else { &[] };
+        }
+
+        let (format, data) = match (format, data) {
+            (Some(f), Some(d)) if f.len() >= 16 => (f, d),
+            _ => return Err(invalid()),
+        };
+
+        let wav = WavData {
+            channels: utils::read_u16_le(&format[2..4]),
+            sample_rate: utils::read_u32_le(&format[4..8]),
+            bits_per_sample: utils::read_u16_le(&format[14..16]),
+            data: data,
+        };
+
+        // Only uncompressed PCM (format 1) is supported.
+        if utils::read_u16_le(&format[..2]) != 1 || wav.channels == 0 ||
+           wav.sample_rate == 0 || (wav.bits_per_sample != 8 && wav.bits_per_sample != 16) {
+            return Err(invalid())
+        }
+
+        Ok(wav)
+    }
+
+    /// Samples (of all channels) normalized to the range [-1, 1].
+    fn samples(&self) -> Vec<f32> {
+        if self.bits_per_sample == 8 {
+            // 8-bit samples are unsigned.
+            self.data.iter().map(|&b| (b as f32 - 128.0) / 128.0).collect()
+        } else {
+            self.data.chunks(2).filter(|c| c.len() == 2).map(|c| {
+                utils::read_u16_le(c) as i16 as f32 / 32768.0
+            }).collect()
+        }
+    }
+}
+
+#[derive(Clone)]
+/// Represents a video file along with the metadata required by Wire clients.
+pub struct Video {
+    mime: Mime,
+    meta: VideoMeta,
+    data: Vec<u8>,
+}
+
+#[derive(Clone, Copy)]
+/// Metadata required for a video file to be rendered in Wire.
+pub struct VideoMeta {
+    pub width: u32,
+    pub height: u32,
+    pub duration_in_millis: u64,
+}
+
+impl Video {
+    /// Create a video asset (of any format) with known metadata.
+    pub fn new(data: Vec<u8>, mime: Mime, meta: VideoMeta) -> Self {
+        Video {
+            mime: mime,
+            meta: meta,
+            data: data,
+        }
+    }
+
+    pub fn mime(&self) -> &Mime {
+        &self.mime
+    }
+
+    pub fn metadata(&self) -> VideoMeta {
+        self.meta
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    fn event_with_time(time: Option<&str>) -> MessageData {
+        let time = time.map(|t| format!(r#", "time": "{}""#, t)).unwrap_or_default();
+        let event = format!(r#"{{"type": "conversation.rename", "conversation": "conv",
+                                 "from": "alice", "data": {{"name": "Lunch"}}{}}}"#, time);
+        serde_json::from_str(&event).unwrap()
+    }
+
+    #[test]
+    fn test_event_time() {
+        let event = event_with_time(Some("2018-05-20T10:20:30.456Z"));
+        assert_eq!(event.time.to_rfc3339(), "2018-05-20T10:20:30.456+00:00");
+
+        // Missing or malformed times shouldn't fail the whole event.
+        let before = Utc::now();
+        assert!(event_with_time(None).time >= before);
+        assert!(event_with_time(Some("yesterday")).time >= before);
+    }
+
+    fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut bytes = id.to_owned();
+        let size = data.len() as u32;
+        bytes.extend_from_slice(&[size as u8, (size >> 8) as u8, (size >> 16) as u8, (size >> 24) as u8]);
+        bytes.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            bytes.push(0);
+        }
+
+        bytes
+    }
+
+    fn format(kind: u16, channels: u16, rate: u32, bits: u16) -> Vec<u8> {
+        let block = channels * bits / 8;
+        let byte_rate = rate * block as u32;
+        let mut bytes = vec![kind as u8, (kind >> 8) as u8, channels as u8, (channels >> 8) as u8];
+        for value in &[rate, byte_rate] {
+            bytes.extend_from_slice(&[*value as u8, (*value >> 8) as u8,
+                                      (*value >> 16) as u8, (*value >> 24) as u8]);
+        }
+
+        bytes.extend_from_slice(&[block as u8, (block >> 8) as u8, bits as u8, (bits >> 8) as u8]);
+        chunk(b"fmt ", &bytes)
+    }
+
+    fn wav(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let body = chunks.concat();
+        let mut bytes = b"RIFF".to_vec();
+        let size = body.len() as u32 + 4;
+        bytes.extend_from_slice(&[size as u8, (size >> 8) as u8, (size >> 16) as u8, (size >> 24) as u8]);
+        bytes.extend_from_slice(b"WAVE");
+        bytes.extend_from_slice(&body);
+        bytes
+    }
+
+    #[test]
+    fn test_minimal_wav() {
+        // One second of 16-bit mono samples at 1 kHz, getting louder.
+        let samples = (0..1000).flat_map(|i: i16| {
+            let s = i * 32;
+            vec![s as u8, (s >> 8) as u8]
+        }).collect::<Vec<_>>();
+        let bytes = wav(&[format(1, 1, 1000, 16), chunk(b"data", &samples)]);
+        let audio = Audio::from_wav(bytes).unwrap();
+        let meta = audio.metadata();
+        assert_eq!(meta.duration_in_millis, 1000);
+        let loudness = meta.normalized_loudness.as_ref().unwrap();
+        assert_eq!(loudness.len(), LOUDNESS_LEVELS);
+        assert_eq!(loudness[LOUDNESS_LEVELS - 1], 255);
+        assert_eq!(audio.mime().as_ref(), "audio/wav");
+    }
+
+    #[test]
+    fn test_stereo_8bit_wav() {
+        let samples = vec![128; 4000];    // silence
+        let bytes = wav(&[format(1, 2, 2000, 8), chunk(b"data", &samples)]);
+        let audio = Audio::from_wav(bytes).unwrap();
+        assert_eq!(audio.metadata().duration_in_millis, 1000);
+        assert_eq!(audio.metadata().normalized_loudness, Some(vec![0; LOUDNESS_LEVELS]));
+    }
+
+    #[test]
+    fn test_truncated_wav() {
+        let bytes = wav(&[format(1, 1, 1000, 16), chunk(b"data", &[0; 100])]);
+        assert!(WavData::parse(&bytes[..10]).is_err());
+        // header without any chunks
+        assert!(WavData::parse(&bytes[..12]).is_err());
+        // cut in the middle of the format chunk
+        assert!(WavData::parse(&bytes[..20]).is_err());
+        // data chunk shorter than its declared size
+        assert!(WavData::parse(&bytes[..bytes.len() - 1]).is_err());
+        // format chunk shorter than 16 bytes
+        let bytes = wav(&[chunk(b"fmt ", &[1, 0, 1, 0]), chunk(b"data", &[0; 4])]);
+        assert!(WavData::parse(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_unsupported_wav() {
+        let data = chunk(b"data", &[0; 16]);
+        // IEEE float samples
+        assert!(WavData::parse(&wav(&[format(3, 1, 1000, 32), data.clone()])).is_err());
+        // 24-bit PCM
+        assert!(WavData::parse(&wav(&[format(1, 1, 1000, 24), data.clone()])).is_err());
+        // no channels, or no sample rate
+        assert!(WavData::parse(&wav(&[format(1, 0, 1000, 16), data.clone()])).is_err());
+        assert!(WavData::parse(&wav(&[format(1, 1, 0, 16), data.clone()])).is_err());
+        // missing data chunk
+        assert!(WavData::parse(&wav(&[format(1, 1, 1000, 16)])).is_err());
+        // not a WAV file at all
+        let mut bytes = wav(&[format(1, 1, 1000, 16), data]);
+        bytes[8..12].copy_from_slice(b"AVI ");
+        assert!(WavData::parse(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_odd_chunk_sizes() {
+        // An odd-sized chunk (padded to an even length) before the format,
+        // and an odd-sized data chunk without its padding at the end.
+        let list = chunk(b"LIST", &[1, 2, 3]);
+        assert_eq!(list.len(), 12);
+        let mut bytes = wav(&[list, format(1, 1, 1000, 8), chunk(b"data", &[200; 5])]);
+        bytes.pop();
+        let parsed = WavData::parse(&bytes).unwrap();
+        assert_eq!(parsed.channels, 1);
+        assert_eq!(parsed.sample_rate, 1000);
+        assert_eq!(parsed.bits_per_sample, 8);
+        assert_eq!(parsed.data, &[200; 5][..]);
+        assert_eq!(parsed.samples().len(), 5);
+    }
+}
@@ -3,38 +3,37 @@ use futures::{Future, Stream, future};
 use hyper::{Body, Error as HyperError, Headers};
 use hyper::header::{ContentLength, Header};
 use md5::Md5;
-use openssl::rand;
+use openssl::{memcmp, rand};
 use openssl::symm::{self, Cipher};
 use parking_lot::RwLock;
 use sha2::{Sha256, Digest};
+use chrono::{DateTime, Utc};
+use std::cmp;
 use std::fmt::Display;
-use std::path::{Path, PathBuf};
+use std::time::Duration;
 use types::{BerylliumFuture, EncryptData};
 
 pub use uuid_v1::new_v1 as uuid_v1;
 
 lazy_static! {
-    static ref STORE_PATH: RwLock<PathBuf> = RwLock::new(PathBuf::from("."));
     static ref AUTH_TOKEN: RwLock<String> = RwLock::new(String::new());
 }
 
 // NOTE: Setting methods are meant to be called only once (during init)
-pub fn set_store_path<P>(path: P) where P: AsRef<Path> {
-    *STORE_PATH.write() = PathBuf::from(path.as_ref());
-}
-
 pub fn set_auth_token(token: String) {
     *AUTH_TOKEN.write() = token;
 }
 
 #[inline]
-pub fn get_store_path() -> PathBuf {
-    STORE_PATH.read().clone()
+pub fn check_auth_token(token: &str) -> bool {
+    *AUTH_TOKEN.read() == token
 }
 
+/// Milliseconds in the given duration (saturating at `u64::MAX`).
 #[inline]
-pub fn check_auth_token(token: &str) -> bool {
-    *AUTH_TOKEN.read() == token
+pub fn duration_millis(duration: Duration) -> u64 {
+    duration.as_secs().saturating_mul(1000)
+                      .saturating_add(duration.subsec_nanos() as u64 / 1_000_000)
 }
 
 /// Return a `Future` that acquires the accumulated request body.
@@ -133,7 +132,7 @@ pub fn md5_hash(data: &[u8]) -> Vec<u8> {
 
 /// Encrypt the given data with AES cipher (256 bits) in CBC mode
 /// (with the initialization vector at the start). Also compute the
-/// SHA-256 hash of the ciphertext.
+/// SHA-256 hash of the ciphertext (including the IV).
 pub fn encrypt(data: &[u8]) -> BerylliumResult<EncryptData> {
     let cipher = Cipher::aes_256_cbc();
     let mut iv = vec![0; cipher.iv_len().unwrap()];     // 16 bytes
@@ -141,10 +140,10 @@ pub fn encrypt(data: &[u8]) -> BerylliumResult<EncryptData> {
     let mut key = vec![0; cipher.key_len()];    // 32 bytes
     rand::rand_bytes(&mut key)?;
     let mut bytes = symm::encrypt(cipher, &key, Some(&iv), data)?;
-    let hash = Sha256::digest(&bytes);
     // First block is IV
     let mut out = iv.clone();
     out.append(&mut bytes);
+    let hash = Sha256::digest(&out);
 
     Ok(EncryptData {
         key: key,
@@ -152,3 +151,117 @@ pub fn encrypt(data: &[u8]) -> BerylliumResult<EncryptData> {
         hash: Vec::from(hash.as_slice()),
     })
 }
+
+/// Inverse of `encrypt` - verify the SHA-256 hash of the given data, and
+/// decrypt it with the given key (assuming that the first block is IV).
+pub fn decrypt(data: &[u8], key: &[u8], hash: &[u8]) -> BerylliumResult<Vec<u8>> {
+    let digest = Sha256::digest(data);
+    // `memcmp::eq` panics for slices of different lengths.
+    if digest.len() != hash.len() || !memcmp::eq(digest.as_slice(), hash) {
+        return Err(BerylliumError::Other(String::from("SHA-256 hash mismatch")))
+    }
+
+    let cipher = Cipher::aes_256_cbc();
+    let iv_len = cipher.iv_len().unwrap();
+    if key.len() != cipher.key_len() || data.len() < iv_len {
+        return Err(BerylliumError::Other(String::from("Invalid key or data for decryption")))
+    }
+
+    let (iv, bytes) = data.split_at(iv_len);
+    let plain = symm::decrypt(cipher, key, Some(iv), bytes)?;
+    Ok(plain)
+}
+
+#[inline]
+pub fn read_u16_le(bytes: &[u8]) -> u16 {
+    bytes[0] as u16 | (bytes[1] as u16) << 8
+}
+
+#[inline]
+pub fn read_u32_le(bytes: &[u8]) -> u32 {
+    read_u16_le(bytes) as u32 | (read_u16_le(&bytes[2..]) as u32) << 16
+}
+
+/// Compute the normalized loudness of the given amplitudes (ranging from 0 to 1)
+/// by splitting them into the given number of levels (peak amplitude in each level),
+/// and scaling them to 0 - 255 relative to the loudest level. There are fewer
+/// levels only if there aren't enough amplitudes.
+pub fn normalized_loudness(amplitudes: &[f32], levels: usize) -> Vec<u8> {
+    if amplitudes.is_empty() || levels == 0 {
+        return vec![]
+    }
+
+    let levels = cmp::min(levels, amplitudes.len());
+    let peaks = (0..levels).map(|i| {
+        let start = i * amplitudes.len() / levels;
+        let end = (i + 1) * amplitudes.len() / levels;
+        amplitudes[start..end].iter().fold(0.0f32, |acc, &a| acc.max(a))
+    }).collect::<Vec<_>>();
+
+    let max = peaks.iter().fold(0.0f32, |acc, &p| acc.max(p));
+    if max == 0.0 {
+        return vec![0; peaks.len()]
+    }
+
+    peaks.iter().map(|p| (p / max * 255.0).round() as u8).collect()
+}
+
+/// Compute the SHA-256 hash of a text message sent at the given time. This is
+/// how Wire clients verify the messages in quotes. The text is encoded as
+/// UTF-16 (big endian, with byte order mark), followed by the timestamp
+/// (seconds since epoch) as a 64-bit big endian integer.
+pub fn text_hash(text: &str, time: &DateTime<Utc>) -> Vec<u8> {
+    let mut bytes = vec![0xFE, 0xFF];
+    for unit in text.encode_utf16() {
+        bytes.push((unit >> 8) as u8);
+        bytes.push(unit as u8);
+    }
+
+    let timestamp = time.timestamp();
+    for i in (0..8).rev() {
+        bytes.push((timestamp >> (i * 8)) as u8);
+    }
+
+    let digest = Sha256::digest(&bytes);
+    Vec::from(digest.as_slice())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duration_millis() {
+        assert_eq!(duration_millis(Duration::new(2, 345_678_901)), 2345);
+        assert_eq!(duration_millis(Duration::from_secs(u64::max_value())), u64::max_value());
+        assert_eq!(duration_millis(Duration::new(u64::max_value() / 1000, 999_999_999)),
+                   u64::max_value() / 1000 * 1000 + 999);
+    }
+
+    #[test]
+    fn test_read_le() {
+        assert_eq!(read_u16_le(&[0x34, 0x12]), 0x1234);
+        assert_eq!(read_u32_le(&[0x78, 0x56, 0x34, 0x12]), 0x12345678);
+        assert_eq!(read_u32_le(&[0xff, 0xff, 0xff, 0xff]), u32::max_value());
+    }
+
+    #[test]
+    fn test_loudness_length() {
+        assert!(normalized_loudness(&[], 100).is_empty());
+        assert!(normalized_loudness(&[0.5], 0).is_empty());
+        assert_eq!(normalized_loudness(&[0.5; 10], 100).len(), 10);
+        assert_eq!(normalized_loudness(&[0.5; 100], 100).len(), 100);
+        assert_eq!(normalized_loudness(&[0.5; 250], 100).len(), 100);
+        assert_eq!(normalized_loudness(&[0.5; 1001], 100).len(), 100);
+    }
+
+    #[test]
+    fn test_loudness_range() {
+        let amplitudes = (0..500).map(|i| i as f32 / 1000.0).collect::<Vec<_>>();
+        let levels = normalized_loudness(&amplitudes, 100);
+        assert_eq!(levels[0], 2);
+        assert_eq!(levels[99], 255);
+        assert!(levels.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(normalized_loudness(&[0.0; 50], 10), vec![0; 10]);
+    }
+}